@@ -0,0 +1,132 @@
+use crate::config::AuditLogConfig;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+
+/// One generation request as recorded for compliance: who asked which model
+/// for what settings and how many tokens it cost, but never the content.
+#[derive(Serialize)]
+pub struct AuditRecord {
+    /// Unix timestamp in seconds of when the request started.
+    pub timestamp: u64,
+    pub request_id: Option<String>,
+    pub user: Option<String>,
+    pub model: String,
+    pub stream: bool,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub stop_sequences: usize,
+    pub logit_bias_entries: usize,
+    pub n: usize,
+    pub best_of: Option<usize>,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Appends [`AuditRecord`]s as JSON Lines to a file or stdout.
+#[derive(Default)]
+pub struct AuditLog {
+    sink: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AuditLog {
+    pub fn from_config(cfg: &AuditLogConfig) -> anyhow::Result<Self> {
+        if !cfg.enabled {
+            return Ok(Self::default());
+        }
+        let sink: Box<dyn Write + Send> = match &cfg.path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Box::new(LineWriter::new(file))
+            }
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(Self {
+            sink: Some(Mutex::new(sink)),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sink.is_some()
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let Ok(mut line) = serde_json::to_string(record) else {
+            return;
+        };
+        line.push('\n');
+        if let Err(err) = sink.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!(error = %err, "failed to write audit record");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{test_config, MockBackend, TestApp, MODEL};
+    use crate::tokens::TokenCounterKind;
+    use axum::http::Method;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn each_generation_appends_an_audit_line_without_content() {
+        let path = std::env::temp_dir().join(format!("llmis-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let mock = MockBackend::replying(&[" secret", " answer"]).await;
+        let mut cfg = test_config(&mock);
+        cfg.audit_log.enabled = true;
+        cfg.audit_log.path = Some(path.to_string_lossy().into_owned());
+        cfg.token_counter = TokenCounterKind::Whitespace;
+        let app = TestApp::start(cfg).await;
+        let resp = app
+            .request(Method::POST, "/v1/completions")
+            .header("x-request-id", "req-audit")
+            .json(&json!({
+                "model": MODEL,
+                "prompt": "private prompt text",
+                "stream": false,
+                "user": "alice",
+                "temperature": 0.3,
+                "max_tokens": 64,
+                "stop": ["\n"],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = app.chat("private question", json!({"stream": true})).await;
+        crate::testing::sse(resp).await;
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            !text.contains("private") && !text.contains("secret"),
+            "{text}"
+        );
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let first = &lines[0];
+        assert!(first["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(first["request_id"], "req-audit");
+        assert_eq!(first["user"], "alice");
+        assert_eq!(first["model"], MODEL);
+        assert_eq!(first["stream"], false);
+        assert_eq!(first["max_tokens"], 64);
+        assert_eq!(first["temperature"].as_f64().unwrap() as f32, 0.3);
+        assert_eq!(first["stop_sequences"], 1);
+        assert_eq!(first["n"], 1);
+        assert_eq!(first["prompt_tokens"], 3);
+        assert_eq!(first["completion_tokens"], 2);
+        assert_eq!(lines[1]["stream"], true);
+        assert_eq!(lines[1]["completion_tokens"], 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
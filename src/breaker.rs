@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Requests fail fast until the cooldown elapses.
+    Open,
+    /// One probe request is in flight; its outcome closes or reopens.
+    HalfOpen,
+}
+
+/// Trips after `threshold` consecutive backend failures so a dead backend
+/// is shed immediately instead of every request waiting on it.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+struct BreakerInner {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 disables the breaker.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::new(BreakerInner {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Lets a request go to the backend, or `None` while the circuit is
+    /// open. Once the cooldown has passed, the first caller becomes the
+    /// half-open probe.
+    pub fn admit(&self) -> Option<Admission<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::HalfOpen => return None,
+            CircuitState::Open => {
+                let cooled = inner
+                    .opened_at
+                    .is_some_and(|at| at.elapsed() >= self.cooldown);
+                if !cooled {
+                    return None;
+                }
+                inner.state = CircuitState::HalfOpen;
+                true
+            }
+        };
+        Some(Admission {
+            breaker: self,
+            probe,
+        })
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = CircuitState::Closed;
+        inner.failures = 0;
+        inner.opened_at = None;
+    }
+
+    fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        if inner.state == CircuitState::HalfOpen || inner.failures >= self.threshold {
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    /// Reopens a half-open circuit whose probe gave up without an outcome.
+    fn abandon_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen {
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+/// A request [`CircuitBreaker::admit`] let through, to be settled with
+/// [`success`](Self::success) or [`failure`](Self::failure). A half-open
+/// probe dropped unsettled, say by a deadline while the backend hangs,
+/// reopens the circuit, so another probe follows the next cooldown.
+pub struct Admission<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+}
+
+impl Admission<'_> {
+    pub fn success(mut self) {
+        self.probe = false;
+        self.breaker.record_success();
+    }
+
+    pub fn failure(mut self) {
+        self.probe = false;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            self.breaker.abandon_probe();
+        }
+    }
+}
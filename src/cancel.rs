@@ -0,0 +1,45 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+/// Streaming generations that can be cancelled by their response `id`.
+#[derive(Default)]
+pub struct ActiveStreams {
+    tokens: Arc<DashMap<String, CancellationToken>>,
+}
+
+impl ActiveStreams {
+    /// Registers `id` until the returned handle is dropped.
+    pub fn register(&self, id: &str) -> StreamRegistration {
+        let token = CancellationToken::new();
+        self.tokens.insert(id.to_string(), token.clone());
+        StreamRegistration {
+            tokens: self.tokens.clone(),
+            id: id.to_string(),
+            token,
+        }
+    }
+
+    /// Signals the stream with `id` to stop; false if no such stream is running.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.tokens.get(id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct StreamRegistration {
+    tokens: Arc<DashMap<String, CancellationToken>>,
+    id: String,
+    pub token: CancellationToken,
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        self.tokens.remove(&self.id);
+    }
+}
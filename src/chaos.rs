@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+/// Failures to inject into generation requests, set through
+/// `POST /admin/chaos` when `enable_chaos` is on.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct ChaosSettings {
+    /// Percentage of generation requests answered with 503.
+    #[serde(default)]
+    pub error_percent: f64,
+    /// Delay added before every generation request.
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Percentage of streams cut off after their first chunks, without a
+    /// finish reason or `[DONE]`.
+    #[serde(default)]
+    pub drop_percent: f64,
+}
+
+/// Failure injection for exercising client retries and our own error
+/// paths. Everything is off until settings are posted.
+#[derive(Default)]
+pub struct Chaos {
+    settings: RwLock<ChaosSettings>,
+}
+
+impl Chaos {
+    pub fn set(&self, settings: ChaosSettings) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        let ms = self.settings.read().unwrap().latency_ms;
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+
+    pub fn should_fail(&self) -> bool {
+        roll(self.settings.read().unwrap().error_percent)
+    }
+
+    pub fn should_drop(&self) -> bool {
+        roll(self.settings.read().unwrap().drop_percent)
+    }
+}
+
+fn roll(percent: f64) -> bool {
+    percent > 0.0 && rand::random::<f64>() * 100.0 < percent
+}
+
+/// Forwards the first `keep` items of `rx`, then closes the returned
+/// channel as if the connection had dropped.
+pub fn cut_off<T: Send + 'static>(mut rx: mpsc::Receiver<T>, keep: usize) -> mpsc::Receiver<T> {
+    let (tx, out) = mpsc::channel(16);
+    tokio::spawn(async move {
+        for _ in 0..keep {
+            let Some(item) = rx.recv().await else {
+                return;
+            };
+            if tx.send(item).await.is_err() {
+                return;
+            }
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{chunks, sse, test_config, MockBackend, TestApp};
+    use axum::http::Method;
+    use serde_json::{json, Value};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn injected_failures_apply_only_when_chaos_is_enabled() {
+        let mock = MockBackend::start().await;
+        let app = TestApp::start(test_config(&mock)).await;
+        let resp = app
+            .admin(Method::POST, "/admin/chaos")
+            .json(&json!({"error_percent": 100.0}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 404);
+
+        let mut cfg = test_config(&mock);
+        cfg.enable_chaos = true;
+        let app = TestApp::start(cfg).await;
+        let set = |settings: Value| {
+            app.admin(Method::POST, "/admin/chaos")
+                .json(&settings)
+                .send()
+        };
+        assert_eq!(
+            set(json!({"error_percent": 101.0})).await.unwrap().status(),
+            400
+        );
+
+        set(json!({"error_percent": 100.0})).await.unwrap();
+        for _ in 0..3 {
+            let resp = app.chat("hi", json!({"stream": false})).await;
+            assert_eq!(resp.status(), 503);
+            let body: Value = resp.json().await.unwrap();
+            assert_eq!(body["error"], "injected failure");
+        }
+        assert!(mock.generations().is_empty());
+
+        set(json!({"latency_ms": 200})).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(app.chat("hi", json!({"stream": false})).await.status(), 200);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        set(json!({"drop_percent": 100.0})).await.unwrap();
+        let events = sse(app
+            .chat("one two three four", json!({"stream": true}))
+            .await)
+        .await;
+        assert!(events.len() <= 2, "{events:?}");
+        assert!(events.iter().all(|event| event.data != "[DONE]"));
+        assert!(chunks(&events)
+            .iter()
+            .all(|chunk| chunk["choices"][0]["finish_reason"].is_null()));
+
+        set(json!({})).await.unwrap();
+        assert_eq!(app.chat("hi", json!({"stream": false})).await.status(), 200);
+    }
+}
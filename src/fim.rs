@@ -0,0 +1,74 @@
+/// Builds a fill-in-the-middle prompt asking the model for the text between
+/// `prefix` and `suffix`, using the sentinel tokens of the code model family
+/// named by `arch`. `None` when the architecture has no known FIM format.
+pub fn fim_prompt(arch: &str, prefix: &str, suffix: &str) -> Option<String> {
+    let prompt = match arch.to_ascii_lowercase().as_str() {
+        "starcoder" | "starcoder2" | "santacoder" => {
+            format!("<fim_prefix>{prefix}<fim_suffix>{suffix}<fim_middle>")
+        }
+        "codellama" => format!("<PRE> {prefix} <SUF>{suffix} <MID>"),
+        "deepseek-coder" => {
+            format!("<｜fim▁begin｜>{prefix}<｜fim▁hole｜>{suffix}<｜fim▁end｜>")
+        }
+        "qwen2.5-coder" | "codegemma" => {
+            format!("<|fim_prefix|>{prefix}<|fim_suffix|>{suffix}<|fim_middle|>")
+        }
+        _ => return None,
+    };
+    Some(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_config, MockBackend, TestApp};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn code_model_families_get_their_sentinels() {
+        assert_eq!(
+            fim_prompt("StarCoder2", "def f(", "):").as_deref(),
+            Some("<fim_prefix>def f(<fim_suffix>):<fim_middle>")
+        );
+        assert_eq!(
+            fim_prompt("codellama", "a", "b").as_deref(),
+            Some("<PRE> a <SUF>b <MID>")
+        );
+        assert_eq!(fim_prompt("llama", "a", "b"), None);
+    }
+
+    #[tokio::test]
+    async fn suffix_sends_a_fim_prompt_or_400s_without_fim_support() {
+        let mock = MockBackend::replying(&["x + 1"]).await;
+        let mut cfg = test_config(&mock);
+        cfg.models[0].arch = Some("starcoder".to_string());
+        cfg.models[0].prompt_prefix = Some("<guard>".to_string());
+        let app = TestApp::start(cfg).await;
+        let resp = app
+            .complete(
+                "def inc(x):\n    return ",
+                json!({"stream": false, "suffix": "\n"}),
+            )
+            .await;
+        assert_eq!(resp.status(), 200);
+        let sent = &mock.generations()[0]["messages"][0]["content"];
+        assert_eq!(
+            sent,
+            "<fim_prefix>def inc(x):\n    return <fim_suffix>\n<fim_middle>"
+        );
+
+        let mut cfg = test_config(&mock);
+        cfg.models[0].arch = Some("llama".to_string());
+        let app = TestApp::start(cfg).await;
+        let resp = app
+            .complete("def inc(x):", json!({"stream": false, "suffix": "\n"}))
+            .await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "model 'm' does not support suffix (fill-in-the-middle)"
+        );
+        assert_eq!(mock.generations().len(), 1);
+    }
+}
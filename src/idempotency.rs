@@ -0,0 +1,215 @@
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use dashmap::mapref::entry::Entry as MapEntry;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+/// Header clients set to make a retried request return the original response.
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+/// Set on responses served from the cache.
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Successful non-streaming responses keyed by `Idempotency-Key`, kept for
+/// a fixed window so client retries don't trigger a second generation.
+/// Each key remembers a fingerprint of the request body it was first used
+/// with, and holds at most `max_entries` keys.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: DashMap<String, Entry>,
+}
+
+enum Entry {
+    /// The first request with this key is still generating.
+    Pending {
+        fingerprint: u64,
+    },
+    Done(CachedResponse),
+}
+
+struct CachedResponse {
+    stored: Instant,
+    fingerprint: u64,
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn response(&self) -> Response {
+        let mut response = (self.status, self.body.clone()).into_response();
+        if let Some(content_type) = &self.content_type {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, content_type.clone());
+        }
+        response
+            .headers_mut()
+            .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// Outcome of looking up a request's `Idempotency-Key`.
+pub enum Lookup<'a> {
+    /// Generate the response and pass it to [`Claim::store`].
+    Claimed(Claim<'a>),
+    /// The response stored for an identical earlier request.
+    Replay(Response),
+    /// The key was first used with a different request body.
+    Mismatch,
+    /// The first request with this key hasn't finished yet.
+    InProgress,
+}
+
+/// Reserves a key while its first request generates; dropped without a
+/// stored response (an error, or the client going away), it frees the key.
+pub struct Claim<'a> {
+    cache: &'a IdempotencyCache,
+    /// `None` when the response won't be cached: the cache is disabled or
+    /// full of requests still in progress.
+    key: Option<String>,
+    fingerprint: u64,
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: DashMap::new(),
+        }
+    }
+
+    pub fn key(headers: &HeaderMap) -> Option<String> {
+        headers
+            .get(IDEMPOTENCY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    }
+
+    /// Looks up `key` for a request whose body hashes to `fingerprint`,
+    /// claiming it when no live response is stored.
+    pub fn begin(&self, key: String, fingerprint: u64) -> Lookup<'_> {
+        let untracked = Claim {
+            cache: self,
+            key: None,
+            fingerprint,
+        };
+        if self.ttl.is_zero() {
+            return Lookup::Claimed(untracked);
+        }
+        let room = self.entries.contains_key(&key) || self.make_room();
+        match self.entries.entry(key.clone()) {
+            MapEntry::Occupied(mut entry) => match entry.get() {
+                Entry::Pending { fingerprint: first } if *first == fingerprint => {
+                    Lookup::InProgress
+                }
+                Entry::Done(cached) if cached.stored.elapsed() < self.ttl => {
+                    if cached.fingerprint == fingerprint {
+                        Lookup::Replay(cached.response())
+                    } else {
+                        Lookup::Mismatch
+                    }
+                }
+                Entry::Pending { .. } => Lookup::Mismatch,
+                Entry::Done(_) => {
+                    entry.insert(Entry::Pending { fingerprint });
+                    Lookup::Claimed(Claim {
+                        key: Some(key),
+                        ..untracked
+                    })
+                }
+            },
+            MapEntry::Vacant(entry) if room => {
+                entry.insert(Entry::Pending { fingerprint });
+                Lookup::Claimed(Claim {
+                    key: Some(key),
+                    ..untracked
+                })
+            }
+            MapEntry::Vacant(_) => Lookup::Claimed(untracked),
+        }
+    }
+
+    /// Forgets every cached response, returning how many there were.
+    /// Requests still in progress keep their keys.
+    pub fn clear(&self) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| matches!(entry, Entry::Pending { .. }));
+        before - self.entries.len()
+    }
+
+    /// Frees a slot for a new key by dropping expired responses, then the
+    /// oldest stored one. False when every slot is a request in progress.
+    fn make_room(&self) -> bool {
+        if self.entries.len() < self.max_entries {
+            return true;
+        }
+        self.entries.retain(|_, entry| match entry {
+            Entry::Pending { .. } => true,
+            Entry::Done(cached) => cached.stored.elapsed() < self.ttl,
+        });
+        if self.entries.len() < self.max_entries {
+            return true;
+        }
+        let oldest = self
+            .entries
+            .iter()
+            .filter_map(|entry| match entry.value() {
+                Entry::Done(cached) => Some((cached.stored, entry.key().clone())),
+                Entry::Pending { .. } => None,
+            })
+            .min();
+        match oldest {
+            Some((_, key)) => self.entries.remove(&key).is_some(),
+            None => false,
+        }
+    }
+}
+
+impl Claim<'_> {
+    /// Buffers `response` and remembers it under the claimed key if it
+    /// succeeded.
+    pub async fn store(mut self, response: Response) -> Response {
+        let Some(key) = self.key.take() else {
+            return response;
+        };
+        if !response.status().is_success() {
+            self.cache.entries.remove(&key);
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let body = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(body) => body,
+            Err(_) => {
+                self.cache.entries.remove(&key);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        self.cache.entries.insert(
+            key,
+            Entry::Done(CachedResponse {
+                stored: Instant::now(),
+                fingerprint: self.fingerprint,
+                status: parts.status,
+                content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+                body: body.clone(),
+            }),
+        );
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.cache
+                .entries
+                .remove_if(&key, |_, entry| matches!(entry, Entry::Pending { .. }));
+        }
+    }
+}
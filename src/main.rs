@@ -1,17 +1,40 @@
+mod audit;
+mod breaker;
+mod cancel;
+mod chaos;
 mod config;
+mod fim;
+mod idempotency;
+mod injection;
 mod metrics;
 mod model;
+mod openapi;
+mod ratelimit;
+mod repetition;
+mod resume;
 mod routes;
+mod server;
+mod stop;
+mod template;
+#[cfg(test)]
+mod testing;
+mod tokens;
 
-use crate::config::AppConfig;
+use crate::audit::AuditLog;
+use crate::config::{AppConfig, ModelConfig};
+use crate::idempotency::IdempotencyCache;
 use crate::metrics::Metrics;
 use crate::model::ModelManager;
-use crate::config::ModelConfig;
+use crate::resume::ResumableStreams;
 use crate::routes::AppState;
+use axum::Router;
 use clap::Parser;
+use std::future::IntoFuture;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
-use tower_http::trace::TraceLayer;
+use tokio::sync::Notify;
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
@@ -27,7 +50,8 @@ struct Cli {
     /// Name to register the GGUF model under (defaults to "local-llm")
     #[arg(long, default_value = "local-llm")]
     gguf_name: String,
-    /// Model architecture for GGUF (llama|mistral)
+    /// Model architecture for GGUF (llama|mistral, or a code model such as
+    /// starcoder2|codellama to enable fill-in-the-middle)
     #[arg(long, default_value = "llama")]
     gguf_arch: String,
     /// Context length override for GGUF
@@ -60,7 +84,7 @@ async fn main() -> anyhow::Result<()> {
             backend: Some("llama-server".to_string()),
             arch: Some(cli.gguf_arch.clone()),
             context_length: cli.gguf_context,
-            server_url: None,
+            ..Default::default()
         });
         info!(
             target: "llmis",
@@ -69,35 +93,104 @@ async fn main() -> anyhow::Result<()> {
             cli.gguf_arch
         );
     }
-    let metrics = Arc::new(Metrics::default());
-    let manager = Arc::new(ModelManager::new(cfg.limits.clone(), metrics.clone()));
-
-    for model_cfg in cfg.models.clone() {
-        match manager.load_model(model_cfg).await {
-            Ok(summary) => info!(
-                target: "llmis",
-                "loaded model '{}' on {}",
-                summary.name, summary.device
-            ),
-            Err(err) => warn!(target: "llmis", "failed to load model: {err:?}"),
+    if let Err(e) = cfg.limits.validate() {
+        anyhow::bail!("limits.{e}");
+    }
+    if !(cfg.safety.injection_threshold > 0.0 && cfg.safety.injection_threshold <= 1.0) {
+        anyhow::bail!("safety.injection_threshold must be in (0, 1]");
+    }
+    if cfg.server.max_requests_per_connection == Some(0) {
+        anyhow::bail!("server.max_requests_per_connection must be at least 1");
+    }
+    if cfg.max_concurrent_loads == Some(0) {
+        anyhow::bail!("max_concurrent_loads must be at least 1");
+    }
+    if !metrics::valid_prefix(&cfg.metrics_prefix) {
+        anyhow::bail!(
+            "invalid metrics_prefix '{}': use letters, digits and underscores, not starting with a digit",
+            cfg.metrics_prefix
+        );
+    }
+    let state = app_state(&cfg)?;
+    let manager = state.models.clone();
+    let metrics = state.metrics.clone();
+
+    // Load configured models in the background so the listener comes up
+    // immediately; requests for a model still loading get a 503.
+    for model_cfg in &cfg.models {
+        manager.mark_loading(&model_cfg.name);
+        if !cfg.eviction.include_config_models {
+            manager.pin(&model_cfg.name);
         }
     }
+    let startup_models = cfg.models.clone();
+    let startup_manager = manager.clone();
+    tokio::spawn(async move {
+        for model_cfg in startup_models {
+            match startup_manager.load_model(model_cfg, false).await {
+                Ok(summary) => info!(
+                    target: "llmis",
+                    "loaded model '{}' on {}",
+                    summary.name, summary.device
+                ),
+                Err(err) => warn!(target: "llmis", "failed to load model: {err:?}"),
+            }
+        }
+    });
 
-    let state = AppState {
-        config: cfg.clone(),
-        models: manager,
-        metrics: metrics.clone(),
-        safety: cfg.safety.clone(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
+    if let Some(ttl) = cfg.eviction.idle_ttl_seconds {
+        let ttl = Duration::from_secs(ttl);
+        let reaper = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((ttl / 4).max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                for name in reaper.evict_idle(ttl).await {
+                    info!(target: "llmis", "unloaded idle model '{name}'");
+                }
+            }
+        });
+    }
+
+    if cfg.enable_chaos {
+        warn!(target: "llmis", "enable_chaos is set: POST /admin/chaos can inject failures");
+    }
+    let router = app_router(state, &cfg)?;
+
+    let stopping = Arc::new(Notify::new());
+    let shutdown = {
+        let stopping = stopping.clone();
+        async move {
+            shutdown_signal().await;
+            stopping.notify_one();
+        }
     };
+    let grace = cfg.server.shutdown_grace_seconds.map(Duration::from_secs);
+
+    #[cfg(unix)]
+    if let Some(path) = cfg.server.unix_socket.as_deref() {
+        info!(target: "llmis", "listening on unix:{}", path);
+        let serve = server::serve_unix(path, router, shutdown);
+        return server::with_shutdown_grace(serve, stopping, grace, &metrics).await;
+    }
 
-    let router = routes::routes(state).layer(TraceLayer::new_for_http());
     let addr = format!("{}:{}", cfg.server.host, cfg.server.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let listener = server::bind_tcp(&cfg.server).await?;
+    if let Some(tls) = &cfg.server.tls {
+        info!(target: "llmis", "listening on https://{}", addr);
+        let nodelay = cfg.server.tcp_nodelay;
+        let serve = server::serve_tls(listener, tls, nodelay, router, shutdown);
+        return server::with_shutdown_grace(serve, stopping, grace, &metrics).await;
+    }
+
     info!(target: "llmis", "listening on http://{}", addr);
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_signal())
+    let make_service = router.into_make_service_with_connect_info::<server::ConnectionId>();
+    let serve = axum::serve(listener, make_service)
+        .tcp_nodelay(cfg.server.tcp_nodelay)
+        .with_graceful_shutdown(shutdown)
+        .into_future();
+    server::with_shutdown_grace(serve, stopping, grace, &metrics)
         .await
         .map_err(|err| {
             error!(target: "llmis", "server error: {err}");
@@ -106,6 +199,78 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Builds the shared state the routes serve from: metrics, the model
+/// manager (with no models loaded yet) and the request caches.
+fn app_state(cfg: &AppConfig) -> anyhow::Result<AppState> {
+    let metrics = Arc::new(
+        Metrics::default()
+            .with_prefix(cfg.metrics_prefix.clone())
+            .with_prompt_token_buckets(cfg.prompt_token_buckets.clone())
+            .with_queue_wait_buckets(cfg.queue_wait_buckets.clone())
+            .with_prompt_window(cfg.distinct_prompt_window),
+    );
+    let manager = Arc::new(
+        ModelManager::new(cfg.limits.clone(), metrics.clone())
+            .with_suggestion_distance(cfg.model_suggestion_distance)
+            .with_token_counter(cfg.token_counter)
+            .with_default_backend(cfg.default_backend.clone())?
+            .with_circuit_breaker(cfg.circuit_breaker.clone())
+            .with_strict_parsing(cfg.strict_backend_parsing)
+            .with_max_concurrent_loads(cfg.max_concurrent_loads)
+            .with_backend_url_forward_api_key(cfg.backend_url_forward_api_key)
+            .with_pool(
+                Duration::from_secs(cfg.pool_idle_timeout_seconds),
+                cfg.pool_max_idle_per_host,
+            ),
+    );
+    Ok(AppState {
+        config: cfg.clone(),
+        models: manager,
+        metrics,
+        safety: cfg.safety.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        idempotency: Arc::new(IdempotencyCache::new(
+            Duration::from_secs(cfg.idempotency_ttl_seconds),
+            cfg.idempotency_max_entries,
+        )),
+        active_streams: Default::default(),
+        config_hash: config_hash(cfg),
+        audit: Arc::new(AuditLog::from_config(&cfg.audit_log)?),
+        resumable: Arc::new(ResumableStreams::new(Duration::from_secs(
+            cfg.stream_resume_ttl_seconds,
+        ))),
+        deep_readiness: Default::default(),
+        chaos: Default::default(),
+        started: tokio::time::Instant::now(),
+        maintenance: Default::default(),
+    })
+}
+
+/// The routes with the connection limit and access log `cfg` asks for.
+fn app_router(state: AppState, cfg: &AppConfig) -> anyhow::Result<Router> {
+    let access_log_level = cfg.server.access_log_level.parse().map_err(|_| {
+        anyhow::anyhow!(
+            "invalid server.access_log_level '{}'",
+            cfg.server.access_log_level
+        )
+    })?;
+    let mut router = routes::routes(state);
+    if let Some(max) = cfg.server.max_requests_per_connection {
+        router = server::with_connection_limit(router, max);
+    }
+    Ok(server::with_access_log(router, access_log_level))
+}
+
+/// Hashes the effective configuration; API keys are redacted in its
+/// `Debug` output so they don't influence (or leak through) the result.
+/// Config maps are `BTreeMap`s, so the output, and with it the hash, is the
+/// same on every start.
+fn config_hash(cfg: &AppConfig) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    format!("{cfg:?}").hash(&mut hasher);
+    hasher.finish()
+}
+
 fn init_tracing() {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,hyper=warn"));
@@ -1,7 +1,10 @@
+use dashmap::DashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex,
 };
+use std::time::Duration;
 
 #[derive(Default)]
 pub struct Metrics {
@@ -9,6 +12,145 @@ pub struct Metrics {
     tokens_total: AtomicU64,
     active_requests: AtomicU64,
     models_loaded: AtomicU64,
+    model_tokens: DashMap<String, ModelTokens>,
+    /// Time to first token of each model's first request after loading.
+    cold_starts: DashMap<String, f64>,
+    generation_micros: AtomicU64,
+    generations: AtomicU64,
+    prompt_tokens: Histogram,
+    queue_wait: Histogram,
+    prompts: PromptWindow,
+    /// Prepended (with `_`) to every metric name; `llmis` when unset.
+    prefix: Option<String>,
+}
+
+const DEFAULT_PREFIX: &str = "llmis";
+
+/// Whether `prefix` yields valid Prometheus metric names.
+pub fn valid_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Cumulative-bucket histogram in the Prometheus sense.
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// One count per bound plus a final `+Inf` bucket; not cumulative.
+    counts: Vec<AtomicU64>,
+    /// `f64` bits of the running sum.
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    /// Just the `+Inf` bucket, so `_sum` and `_count` are still tracked.
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Histogram {
+    pub fn new(mut bounds: Vec<f64>) -> Self {
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        let counts = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            bounds,
+            counts,
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[idx].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, openmetrics: bool) {
+        write_header(out, name, MetricType::Histogram, help, openmetrics);
+        let mut cumulative = 0;
+        for (idx, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = match self.bounds.get(idx) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            out.push_str(&format!("{name}_bucket{{le=\"{le}\"}} {cumulative}\n"));
+        }
+        out.push_str(&format!(
+            "{name}_sum {}\n",
+            f64::from_bits(self.sum.load(Ordering::Relaxed))
+        ));
+        out.push_str(&format!("{name}_count {cumulative}\n"));
+    }
+}
+
+/// Hashes of the most recent prompts, for the share of them that are
+/// distinct. Memory stays bounded by the window size.
+#[derive(Default)]
+struct PromptWindow {
+    capacity: usize,
+    inner: Mutex<WindowState>,
+}
+
+#[derive(Default)]
+struct WindowState {
+    order: VecDeque<u64>,
+    /// Occurrences of each hash within `order`.
+    counts: HashMap<u64, usize>,
+}
+
+impl PromptWindow {
+    fn observe(&self, prompt: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let hash = fnv1a(prompt.as_bytes());
+        let mut state = self.inner.lock().unwrap();
+        state.order.push_back(hash);
+        *state.counts.entry(hash).or_default() += 1;
+        while state.order.len() > self.capacity {
+            let Some(old) = state.order.pop_front() else {
+                break;
+            };
+            if let Some(count) = state.counts.get_mut(&old) {
+                *count -= 1;
+                if *count == 0 {
+                    state.counts.remove(&old);
+                }
+            }
+        }
+    }
+
+    /// Distinct prompts over prompts in the window; `None` while empty.
+    fn ratio(&self) -> Option<f64> {
+        let state = self.inner.lock().unwrap();
+        (!state.order.is_empty()).then(|| state.counts.len() as f64 / state.order.len() as f64)
+    }
+}
+
+/// 64-bit FNV-1a; cheap and stable, and collisions only nudge the ratio.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Default)]
+struct ModelTokens {
+    prompt: AtomicU64,
+    completion: AtomicU64,
 }
 
 pub struct InflightGuard {
@@ -22,6 +164,49 @@ impl Drop for InflightGuard {
 }
 
 impl Metrics {
+    pub fn with_prefix(mut self, prefix: String) -> Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(DEFAULT_PREFIX)
+    }
+
+    pub fn with_prompt_token_buckets(mut self, bounds: Vec<f64>) -> Self {
+        self.prompt_tokens = Histogram::new(bounds);
+        self
+    }
+
+    pub fn observe_prompt_tokens(&self, tokens: u64) {
+        self.prompt_tokens.observe(tokens as f64);
+    }
+
+    pub fn with_queue_wait_buckets(mut self, bounds: Vec<f64>) -> Self {
+        self.queue_wait = Histogram::new(bounds);
+        self
+    }
+
+    pub fn with_prompt_window(mut self, capacity: usize) -> Self {
+        self.prompts = PromptWindow {
+            capacity,
+            ..Default::default()
+        };
+        self
+    }
+
+    pub fn observe_prompt(&self, prompt: &str) {
+        self.prompts.observe(prompt);
+    }
+
+    pub fn observe_queue_wait(&self, wait: Duration) {
+        self.queue_wait.observe(wait.as_secs_f64());
+    }
+
+    pub fn active_requests(&self) -> u64 {
+        self.active_requests.load(Ordering::Relaxed)
+    }
+
     pub fn guard(self: &Arc<Self>) -> InflightGuard {
         self.active_requests.fetch_add(1, Ordering::Relaxed);
         InflightGuard {
@@ -37,36 +222,388 @@ impl Metrics {
         self.tokens_total.fetch_add(tokens, Ordering::Relaxed);
     }
 
+    pub fn add_model_tokens(&self, model: &str, prompt: u64, completion: u64) {
+        let entry = self.model_tokens.entry(model.to_string()).or_default();
+        entry.prompt.fetch_add(prompt, Ordering::Relaxed);
+        entry.completion.fetch_add(completion, Ordering::Relaxed);
+    }
+
+    pub fn record_cold_start(&self, model: &str, elapsed: Duration) {
+        self.cold_starts
+            .insert(model.to_string(), elapsed.as_secs_f64());
+    }
+
+    /// Drops an unloaded model's cold-start gauge; it is recorded afresh if
+    /// the model is loaded again.
+    pub fn remove_cold_start(&self, model: &str) {
+        self.cold_starts.remove(model);
+    }
+
+    pub fn record_generation(&self, elapsed: Duration) {
+        self.generation_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.generations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mean wall-clock time of completed generations, if any have finished.
+    pub fn avg_generation_secs(&self) -> Option<f64> {
+        let count = self.generations.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        let micros = self.generation_micros.load(Ordering::Relaxed);
+        Some(micros as f64 / count as f64 / 1_000_000.0)
+    }
+
     pub fn set_models_loaded(&self, count: u64) {
         self.models_loaded.store(count, Ordering::Relaxed);
     }
 
     pub fn render_prometheus(&self) -> String {
+        self.render(false)
+    }
+
+    /// OpenMetrics 1.0 text: counter families are named without `_total`
+    /// and the exposition ends with `# EOF`.
+    pub fn render_openmetrics(&self) -> String {
+        let mut out = self.render(true);
+        out.push_str("# EOF\n");
+        out
+    }
+
+    fn render(&self, openmetrics: bool) -> String {
+        let p = self.prefix();
         let mut out = String::new();
-        out.push_str("# HELP llmis_requests_total Total HTTP requests handled\n");
-        out.push_str("# TYPE llmis_requests_total counter\n");
+        write_header(
+            &mut out,
+            &format!("{p}_requests_total"),
+            MetricType::Counter,
+            "Total HTTP requests handled",
+            openmetrics,
+        );
         out.push_str(&format!(
-            "llmis_requests_total {}\n",
+            "{p}_requests_total {}\n",
             self.requests_total.load(Ordering::Relaxed)
         ));
-        out.push_str("# HELP llmis_tokens_total Tokens emitted by generators\n");
-        out.push_str("# TYPE llmis_tokens_total counter\n");
+        write_header(
+            &mut out,
+            &format!("{p}_tokens_total"),
+            MetricType::Counter,
+            "Tokens emitted by generators",
+            openmetrics,
+        );
         out.push_str(&format!(
-            "llmis_tokens_total {}\n",
+            "{p}_tokens_total {}\n",
             self.tokens_total.load(Ordering::Relaxed)
         ));
-        out.push_str("# HELP llmis_active_requests Active requests in flight\n");
-        out.push_str("# TYPE llmis_active_requests gauge\n");
+        write_header(
+            &mut out,
+            &format!("{p}_active_requests"),
+            MetricType::Gauge,
+            "Active requests in flight",
+            openmetrics,
+        );
         out.push_str(&format!(
-            "llmis_active_requests {}\n",
+            "{p}_active_requests {}\n",
             self.active_requests.load(Ordering::Relaxed)
         ));
-        out.push_str("# HELP llmis_models_loaded Models currently registered\n");
-        out.push_str("# TYPE llmis_models_loaded gauge\n");
+        write_header(
+            &mut out,
+            &format!("{p}_models_loaded"),
+            MetricType::Gauge,
+            "Models currently registered",
+            openmetrics,
+        );
         out.push_str(&format!(
-            "llmis_models_loaded {}\n",
+            "{p}_models_loaded {}\n",
             self.models_loaded.load(Ordering::Relaxed)
         ));
+        write_header(
+            &mut out,
+            &format!("{p}_generation_seconds"),
+            MetricType::Summary,
+            "Wall-clock time of completed generations",
+            openmetrics,
+        );
+        out.push_str(&format!(
+            "{p}_generation_seconds_sum {}\n",
+            self.generation_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        out.push_str(&format!(
+            "{p}_generation_seconds_count {}\n",
+            self.generations.load(Ordering::Relaxed)
+        ));
+
+        // The OpenMetrics name of the prompt token counter family is already
+        // `<prefix>_prompt_tokens`, so the histogram needs a distinct one there.
+        let prompt_tokens = if openmetrics {
+            format!("{p}_prompt_tokens_per_request")
+        } else {
+            format!("{p}_prompt_tokens")
+        };
+        self.prompt_tokens.render(
+            &mut out,
+            &prompt_tokens,
+            "Prompt size in tokens per generation request",
+            openmetrics,
+        );
+        self.queue_wait.render(
+            &mut out,
+            &format!("{p}_queue_wait_seconds"),
+            "Time requests waited for a concurrency permit",
+            openmetrics,
+        );
+
+        if self.prompts.capacity > 0 {
+            write_header(
+                &mut out,
+                &format!("{p}_distinct_prompt_ratio"),
+                MetricType::Gauge,
+                "Share of distinct prompts among the most recent ones",
+                openmetrics,
+            );
+            if let Some(ratio) = self.prompts.ratio() {
+                out.push_str(&format!("{p}_distinct_prompt_ratio {ratio}\n"));
+            }
+        }
+
+        let mut models: Vec<(String, u64, u64)> = self
+            .model_tokens
+            .iter()
+            .map(|entry| {
+                (
+                    entry.key().clone(),
+                    entry.prompt.load(Ordering::Relaxed),
+                    entry.completion.load(Ordering::Relaxed),
+                )
+            })
+            .collect();
+        models.sort();
+        write_header(
+            &mut out,
+            &format!("{p}_prompt_tokens_total"),
+            MetricType::Counter,
+            "Prompt tokens submitted, by model",
+            openmetrics,
+        );
+        for (model, prompt, _) in &models {
+            out.push_str(&format!(
+                "{p}_prompt_tokens_total{{model=\"{}\"}} {}\n",
+                escape_label(model),
+                prompt
+            ));
+        }
+        write_header(
+            &mut out,
+            &format!("{p}_completion_tokens_total"),
+            MetricType::Counter,
+            "Completion tokens generated, by model",
+            openmetrics,
+        );
+        for (model, _, completion) in &models {
+            out.push_str(&format!(
+                "{p}_completion_tokens_total{{model=\"{}\"}} {}\n",
+                escape_label(model),
+                completion
+            ));
+        }
+
+        let mut cold_starts: Vec<(String, f64)> = self
+            .cold_starts
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        cold_starts.sort_by(|a, b| a.0.cmp(&b.0));
+        write_header(
+            &mut out,
+            &format!("{p}_cold_start_seconds"),
+            MetricType::Gauge,
+            "Time to first token of the first request after a model loaded",
+            openmetrics,
+        );
+        for (model, secs) in &cold_starts {
+            out.push_str(&format!(
+                "{p}_cold_start_seconds{{model=\"{}\"}} {}\n",
+                escape_label(model),
+                secs
+            ));
+        }
         out
     }
 }
+
+/// The `# TYPE` of a metric family.
+#[derive(Clone, Copy)]
+enum MetricType {
+    Counter,
+    Gauge,
+    Summary,
+    Histogram,
+}
+
+impl MetricType {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Summary => "summary",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: MetricType, help: &str, openmetrics: bool) {
+    let family = match kind {
+        MetricType::Counter if openmetrics => name.strip_suffix("_total").unwrap_or(name),
+        _ => name,
+    };
+    out.push_str(&format!("# HELP {family} {help}\n"));
+    out.push_str(&format!("# TYPE {family} {}\n", kind.as_str()));
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::testing::{test_config, MockBackend, TestApp};
+    use crate::tokens::TokenCounterKind;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn openmetrics_names_counter_families_without_total_and_ends_with_eof() {
+        let metrics = Metrics::default();
+        metrics.inc_request();
+        metrics.add_model_tokens("m", 3, 2);
+        let text = metrics.render_openmetrics();
+        assert!(text.ends_with("# EOF\n"), "{text}");
+        assert!(text.contains("# TYPE llmis_requests counter\nllmis_requests_total 1\n"));
+        assert!(text.contains("# TYPE llmis_prompt_tokens counter\n"));
+        assert!(text.contains("llmis_prompt_tokens_total{model=\"m\"} 3\n"));
+        assert!(text.contains("# TYPE llmis_prompt_tokens_per_request histogram\n"));
+        for line in text.lines().filter(|line| line.starts_with("# TYPE")) {
+            assert!(!line.contains("_total "), "{line}");
+        }
+        let legacy = metrics.render_prometheus();
+        assert!(legacy.contains("# TYPE llmis_requests_total counter\n"));
+        assert!(!legacy.contains("# EOF"));
+
+        let (app, _mock) = TestApp::with_mock().await;
+        let resp = app.get("/metrics?format=openmetrics").await;
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("application/openmetrics-text"));
+        assert!(resp.text().await.unwrap().ends_with("# EOF\n"));
+        let resp = app.get("/metrics").await;
+        assert!(resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+    }
+
+    #[tokio::test]
+    async fn prompt_sizes_land_in_their_buckets() {
+        let mock = MockBackend::replying(&["ok"]).await;
+        let cfg = AppConfig {
+            token_counter: TokenCounterKind::Whitespace,
+            prompt_token_buckets: vec![4.0, 2.0, 8.0],
+            ..test_config(&mock)
+        };
+        let app = TestApp::start(cfg).await;
+        for prompt in [
+            "one",
+            "one two",
+            "a b c",
+            "a b c d e f",
+            "a b c d e f g h i j",
+        ] {
+            let resp = app.complete(prompt, json!({"stream": false})).await;
+            assert_eq!(resp.status(), 200);
+        }
+        assert_eq!(mock.generations().len(), 5);
+        let text = app.get("/metrics").await.text().await.unwrap();
+        for line in [
+            "llmis_prompt_tokens_bucket{le=\"2\"} 2\n",
+            "llmis_prompt_tokens_bucket{le=\"4\"} 3\n",
+            "llmis_prompt_tokens_bucket{le=\"8\"} 4\n",
+            "llmis_prompt_tokens_bucket{le=\"+Inf\"} 5\n",
+            "llmis_prompt_tokens_sum 22\n",
+            "llmis_prompt_tokens_count 5\n",
+        ] {
+            assert!(text.contains(line), "{line} missing from {text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_prefix_names_every_metric() {
+        assert!(valid_prefix("acme_ai"));
+        assert!(!valid_prefix("9lives"));
+        assert!(!valid_prefix("acme-ai"));
+
+        let mock = MockBackend::replying(&["ok"]).await;
+        let cfg = AppConfig {
+            metrics_prefix: "acme".to_string(),
+            ..test_config(&mock)
+        };
+        let app = TestApp::start(cfg).await;
+        app.chat("hi", json!({"stream": false})).await;
+        for format in ["", "?format=openmetrics"] {
+            let text = app
+                .get(&format!("/metrics{format}"))
+                .await
+                .text()
+                .await
+                .unwrap();
+            assert!(!text.contains("llmis"), "{text}");
+            let names = text
+                .lines()
+                .filter(|line| !line.starts_with('#') && !line.is_empty())
+                .chain(text.lines().filter_map(|line| {
+                    line.strip_prefix("# TYPE ")
+                        .or_else(|| line.strip_prefix("# HELP "))
+                }));
+            for line in names {
+                assert!(line.starts_with("acme_"), "{line}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn distinct_prompt_ratio_reflects_repeats_within_the_window() {
+        let metrics = Metrics::default().with_prompt_window(4);
+        assert!(!metrics
+            .render_prometheus()
+            .contains("\nllmis_distinct_prompt_ratio "));
+        for prompt in ["a", "a", "b", "c"] {
+            metrics.observe_prompt(prompt);
+        }
+        assert!(metrics
+            .render_prometheus()
+            .contains("\nllmis_distinct_prompt_ratio 0.75\n"));
+        // The repeated prompt has left the window.
+        for prompt in ["d", "e"] {
+            metrics.observe_prompt(prompt);
+        }
+        assert!(metrics
+            .render_prometheus()
+            .contains("\nllmis_distinct_prompt_ratio 1\n"));
+
+        let mock = MockBackend::replying(&["ok"]).await;
+        let app = TestApp::start(test_config(&mock)).await;
+        for prompt in ["same", "same", "same", "other"] {
+            app.complete(prompt, json!({"stream": false})).await;
+        }
+        let text = app.get("/metrics").await.text().await.unwrap();
+        assert!(
+            text.contains("\nllmis_distinct_prompt_ratio 0.5\n"),
+            "{text}"
+        );
+    }
+}
@@ -1,26 +1,99 @@
-use crate::config::{LimitConfig, ModelConfig};
+use crate::breaker::{CircuitBreaker, CircuitState};
+use crate::config::{
+    CircuitBreakerConfig, LimitConfig, ModelConfig, OverflowBehavior, SafetyConfig,
+};
+use crate::fim::fim_prompt;
 use crate::metrics::Metrics;
+use crate::ratelimit::TokenBucket;
+use crate::template::ChatTemplate;
+use crate::tokens::{
+    BackendCounter, HeuristicCounter, TokenCounter, TokenCounterKind, WhitespaceCounter,
+};
 use async_trait::async_trait;
-use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
 use futures::stream::{BoxStream, Stream};
-use serde::Serialize;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_stream::wrappers::ReceiverStream;
-use futures::StreamExt;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ModelSummary {
     pub name: String,
     pub device: String,
     pub backend: String,
     pub quantization: Option<String>,
     pub max_concurrent: usize,
+    pub capabilities: ModelCapabilities,
+}
+
+/// What a model can do, so clients can adapt without trial requests.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelCapabilities {
+    pub streaming: bool,
+    pub supports_embeddings: bool,
+    pub supports_tools: bool,
+    /// Whether `prompt_logprobs` can be requested.
+    pub supports_prompt_logprobs: bool,
+    pub context_length: usize,
+    /// Largest `max_tokens` a request will be granted.
+    pub max_tokens: usize,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelStatus {
+    pub name: String,
+    pub backend: String,
+    pub max_concurrent: usize,
+    pub available_permits: usize,
+    pub queued: usize,
+    /// Most recent backend failure, cleared by the next successful request.
+    pub last_error: Option<LastError>,
+    pub circuit: CircuitState,
+}
+
+/// What a model can take on right now, for external schedulers.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ModelCapacity {
+    pub name: String,
+    pub available_permits: usize,
+    /// Free waiter slots; always 0 unless the model queues on overflow.
+    pub queue_slots: usize,
+    pub circuit: CircuitState,
+    /// Whether a request sent now would be admitted or queued rather than
+    /// rejected.
+    pub accepting: bool,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LastError {
+    pub message: String,
+    /// Unix timestamp in seconds.
+    pub at: u64,
+}
+
+impl LastError {
+    fn now(message: String) -> Self {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self { message, at }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,7 +102,21 @@ pub struct ModelInfo {
     pub device: String,
     pub backend: String,
     pub quantization: Option<String>,
-    pub max_concurrent: usize,
+    pub overflow: OverflowBehavior,
+    pub capabilities: ModelCapabilities,
+}
+
+impl ModelInfo {
+    pub fn summary(&self, max_concurrent: usize) -> ModelSummary {
+        ModelSummary {
+            name: self.name.clone(),
+            device: self.device.clone(),
+            backend: self.backend.clone(),
+            quantization: self.quantization.clone(),
+            max_concurrent,
+            capabilities: self.capabilities.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,30 +125,158 @@ pub struct GenerateParams {
     pub max_tokens: usize,
     pub temperature: f32,
     pub top_p: f32,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
     pub stop: Option<Vec<String>>,
+    pub cache_prompt: bool,
+    /// Routes every request of a session to the same backend replica.
+    pub session_id: Option<String>,
+    pub logit_bias: Option<HashMap<String, f32>>,
+    /// Ask the backend for prefill progress during long prompts.
+    pub return_progress: bool,
+    /// Backend-specific fields merged into the request body as-is.
+    pub extra: Option<serde_json::Map<String, Value>>,
+    /// Ask the backend for per-token log-probabilities.
+    pub logprobs: bool,
+    /// Correlates backend failures in the logs with the client request.
+    pub request_id: Option<String>,
+    /// OpenAI-style tool definitions the model may call.
+    pub tools: Option<Vec<Value>>,
+    pub tool_choice: Option<Value>,
+    /// Server to send this request to instead of the model's own, already
+    /// checked against `backend_url_allowlist`.
+    pub backend_url: Option<String>,
+    pub seed: Option<u64>,
+    /// Times to reissue the request when the backend stream drops before
+    /// finishing, skipping the text already forwarded. Only sound for
+    /// seeded requests, which replay the same text.
+    pub reconnects: u32,
+    /// Keep a matched stop sequence at the end of the output. Stops are
+    /// then matched only by us, since the backend always trims them.
+    pub include_stop: bool,
+    /// Alternatives per prompt position to return log-probabilities for;
+    /// only vLLM computes them.
+    pub prompt_logprobs: Option<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct TokenEvent {
     pub token: String,
     pub finished: bool,
+    /// Fraction of the prompt processed so far, for prefill progress events
+    /// that carry no text.
+    pub progress: Option<f32>,
+    /// Summed log-probability of the tokens in this event, when requested.
+    pub logprob: Option<f32>,
+    /// Incremental tool calls: the function name first, then fragments of
+    /// its JSON arguments, keyed by `index`.
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+    /// Why the backend stopped (`"stop"`, `"length"`, `"tool_calls"`, ...),
+    /// on the finishing event when it said.
+    pub finish_reason: Option<String>,
+    /// Log-probabilities of the prompt tokens, as the backend reported them
+    /// (vLLM's per-position maps), when requested.
+    pub prompt_logprobs: Option<Value>,
+    /// Set on the finishing event of a stream cut short because the backend
+    /// sent an event that failed strict parsing.
+    pub malformed: bool,
 }
 
+/// One fragment of a streamed tool call, in OpenAI's `delta.tool_calls`
+/// shape. Fragments with the same `index` concatenate into one call.
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct FunctionCallDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+/// Folds streamed fragments into `calls`, one entry per `index`, with the
+/// argument fragments concatenated.
+pub fn merge_tool_calls(calls: &mut Vec<ToolCallDelta>, deltas: Vec<ToolCallDelta>) {
+    for delta in deltas {
+        let Some(call) = calls.iter_mut().find(|c| c.index == delta.index) else {
+            calls.push(delta);
+            continue;
+        };
+        call.id = call.id.take().or(delta.id);
+        call.kind = call.kind.take().or(delta.kind);
+        let Some(fragment) = delta.function else {
+            continue;
+        };
+        let function = call.function.get_or_insert_with(Default::default);
+        if function.name.is_none() {
+            function.name = fragment.name;
+        }
+        if let Some(arguments) = fragment.arguments {
+            function
+                .arguments
+                .get_or_insert_with(String::new)
+                .push_str(&arguments);
+        }
+    }
+}
+
+/// A step of loading a model, streamed by `/admin/models/load/stream`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum LoadStage {
+    /// Reaching the backend server (and probing it for `backend = "auto"`).
+    Connecting,
+    /// The server is up but still loading the model weights.
+    WarmingUp,
+    /// The model is registered and serving requests.
+    Ready { model: ModelSummary },
+}
+
+/// Receives the stages of a load someone is following. Stages a slow
+/// follower hasn't taken yet are dropped rather than queued without bound.
+pub type LoadProgress = mpsc::Sender<LoadStage>;
+
 #[derive(Error, Debug)]
 pub enum ModelError {
     #[error("model not found: {0}")]
     NotFound(String),
     #[error("model overloaded")]
     Overloaded,
+    #[error("rate limit exceeded")]
+    RateLimited { retry_after: u64 },
+    #[error("model loading: {0}")]
+    Loading(String),
     #[error("backend error: {0}")]
     Backend(String),
+    #[error("template error: {0}")]
+    Template(String),
+    /// A template that loaded fine failed on a request's messages.
+    #[error("template render error: {0}")]
+    TemplateRender(String),
+    #[error("invalid model config: {0}")]
+    InvalidConfig(String),
+    #[error("model already loaded: {0}")]
+    AlreadyLoaded(String),
 }
 
 pub type ModelStream = GuardedStream<BoxStream<'static, TokenEvent>>;
 
 #[async_trait]
 pub trait ModelBackend: Send + Sync {
-    async fn load(&self, cfg: &ModelConfig) -> Result<(), ModelError>;
+    async fn load(
+        &self,
+        cfg: &ModelConfig,
+        progress: Option<&LoadProgress>,
+    ) -> Result<(), ModelError>;
     async fn unload(&self) -> Result<(), ModelError>;
     async fn generate_stream(
         &self,
@@ -72,111 +287,949 @@ pub trait ModelBackend: Send + Sync {
 pub struct ModelHandle {
     pub info: ModelInfo,
     backend: Arc<dyn ModelBackend>,
+    counter: Arc<dyn TokenCounter>,
     semaphore: Arc<Semaphore>,
+    /// Shared by every model when `limits.global_max_concurrent` is set.
+    global: Option<Arc<Semaphore>>,
+    /// Shared by the models on this device when it has a
+    /// `limits.device_max_concurrent` entry.
+    device: Option<Arc<Semaphore>>,
+    /// Permits `semaphore` is sized for; follows `limits.max_concurrent`
+    /// unless the model sets its own.
+    max_concurrent: AtomicUsize,
+    /// Permits a shrink still has to take back from running generations.
+    /// A later raise cancels these first instead of adding new ones.
+    retiring: Arc<AtomicUsize>,
+    queue_depth: AtomicUsize,
+    /// The model's own `max_concurrent` and `queue_depth`, which runtime
+    /// limit updates leave alone.
+    own_max_concurrent: Option<usize>,
+    own_queue_depth: Option<usize>,
+    queued: Arc<AtomicUsize>,
+    inflight: Arc<InflightRegistry>,
+    last_used: Mutex<Instant>,
+    /// Shared with running streams, which clear it when a generation
+    /// finishes cleanly.
+    last_error: Arc<Mutex<Option<LastError>>>,
+    breaker: CircuitBreaker,
+    safety: Option<SafetyConfig>,
+    template: Option<Arc<ChatTemplate>>,
+    request_timeout: Option<Duration>,
+    prompt_prefix: Option<String>,
+    prompt_suffix: Option<String>,
+    arch: Option<String>,
+    metrics: Arc<Metrics>,
+    /// Set until the first request after loading has started generating.
+    cold: AtomicBool,
+    /// Requests-per-minute cap from `rate_limit_rpm`, checked before queueing.
+    rate_limit: Option<TokenBucket>,
+}
+
+/// How long a `shed_oldest` request waits for the cancelled generation to
+/// release its permit before giving up as overloaded.
+const SHED_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Takes permits back from `semaphore` one at a time as generations release
+/// them, until `retiring` is paid off. A permit taken after a raise cancelled
+/// the rest goes straight back.
+async fn retire_permits(semaphore: Arc<Semaphore>, retiring: Arc<AtomicUsize>) {
+    while let Ok(permit) = semaphore.clone().acquire_owned().await {
+        if take_up_to(&retiring, 1) == 0 {
+            break;
+        }
+        permit.forget();
+    }
+}
+
+/// Subtracts up to `max` from `counter`, returning how much it took.
+fn take_up_to(counter: &AtomicUsize, max: usize) -> usize {
+    let previous = counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            Some(n - n.min(max))
+        })
+        .unwrap_or_default();
+    previous.min(max)
 }
 
 impl ModelHandle {
-    pub async fn stream(
+    /// Counts a request against `rate_limit_rpm`, however many generations
+    /// it fans out to.
+    fn take_rate_limit(&self) -> Result<(), ModelError> {
+        match &self.rate_limit {
+            Some(bucket) => bucket.try_take().map_err(|wait| ModelError::RateLimited {
+                retry_after: wait.as_secs_f64().ceil().max(1.0) as u64,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn stream(&self, params: GenerateParams) -> Result<ModelStream, ModelError> {
+        let mut streams = self.streams(params, 1).await?;
+        Ok(streams.remove(0))
+    }
+
+    /// Starts `n` generations of `params`, taking the permits for all of
+    /// them in one step: requests each holding some while waiting for the
+    /// rest could wait on one another forever.
+    pub async fn streams(
+        &self,
+        params: GenerateParams,
+        n: usize,
+    ) -> Result<Vec<ModelStream>, ModelError> {
+        self.touch();
+        let waiting = Instant::now();
+        let mut permit = self.acquire(n).await?;
+        let mut shared = Vec::new();
+        shared.extend(self.acquire_shared(self.device.as_ref(), n).await?);
+        shared.extend(self.acquire_shared(self.global.as_ref(), n).await?);
+        self.metrics.observe_queue_wait(waiting.elapsed());
+        let mut streams = Vec::with_capacity(n);
+        for _ in 1..n {
+            let one = permit.split(1).expect("a permit per generation");
+            let shared_one = shared
+                .iter_mut()
+                .map(|permits| permits.split(1).expect("a permit per generation"))
+                .collect();
+            streams.push(self.start(params.clone(), one, shared_one).await?);
+        }
+        streams.push(self.start(params, permit, shared).await?);
+        Ok(streams)
+    }
+
+    /// Sends one generation to the backend, once its permits are held.
+    async fn start(
         &self,
         params: GenerateParams,
+        permit: OwnedSemaphorePermit,
+        shared: Vec<OwnedSemaphorePermit>,
     ) -> Result<ModelStream, ModelError> {
-        let permit = self
-            .semaphore
-            .clone()
-            .try_acquire_owned()
-            .map_err(|_| ModelError::Overloaded)?;
+        // A `backend_url` override is another server; how it fares says
+        // nothing about the configured backend's health.
+        let admission = match params.backend_url {
+            Some(_) => None,
+            None => match self.breaker.admit() {
+                Some(admission) => Some(admission),
+                None => return Err(ModelError::Backend("circuit open".to_string())),
+            },
+        };
+        let cold = self.cold.swap(false, Ordering::Relaxed);
+        let started = Instant::now();
+        let stream = match self.backend.generate_stream(params).await {
+            Ok(stream) => match admission {
+                Some(admission) => {
+                    admission.success();
+                    self.track_errors(stream)
+                }
+                None => stream,
+            },
+            Err(err) => {
+                if let Some(admission) = admission {
+                    admission.failure();
+                    self.record_error(&err);
+                }
+                if cold {
+                    self.cold.store(true, Ordering::Relaxed);
+                }
+                return Err(err);
+            }
+        };
+        let stream = if cold {
+            self.time_first_token(stream, started)
+        } else {
+            stream
+        };
+        Ok(GuardedStream::new(
+            stream,
+            permit,
+            shared,
+            self.inflight.register(),
+        ))
+    }
+
+    /// Records the cold-start metric when the first text (or the end of the
+    /// generation) arrives; prefill progress events don't count.
+    fn time_first_token(
+        &self,
+        stream: BoxStream<'static, TokenEvent>,
+        started: Instant,
+    ) -> BoxStream<'static, TokenEvent> {
+        let metrics = self.metrics.clone();
+        let model = self.info.name.clone();
+        let mut pending = true;
+        Box::pin(stream.inspect(move |event| {
+            if pending && (!event.token.is_empty() || event.finished) {
+                pending = false;
+                metrics.record_cold_start(&model, started.elapsed());
+            }
+        }))
+    }
+
+    /// Clears the last error once a generation finishes, or records one if
+    /// it finishes with a malformed response.
+    fn track_errors(
+        &self,
+        stream: BoxStream<'static, TokenEvent>,
+    ) -> BoxStream<'static, TokenEvent> {
+        let last_error = self.last_error.clone();
+        Box::pin(stream.inspect(move |event| {
+            if !event.finished {
+                return;
+            }
+            *last_error.lock().unwrap() = event
+                .malformed
+                .then(|| LastError::now("malformed response".to_string()));
+        }))
+    }
+
+    /// Unloads a handle that is no longer registered; failures are only
+    /// logged since the replacement is already serving.
+    async fn unload_backend(&self) {
+        if let Err(err) = self.backend.unload().await {
+            tracing::warn!(model = %self.info.name, error = %err, "failed to unload backend");
+        }
+    }
+
+    fn record_error(&self, err: &ModelError) {
+        if let ModelError::Backend(msg) = err {
+            *self.last_error.lock().unwrap() = Some(LastError::now(msg.clone()));
+        }
+    }
 
-        let stream = self.backend.generate_stream(params).await?;
-        Ok(GuardedStream::new(stream, permit))
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    /// Idle for `ttl` with nothing running or waiting.
+    fn is_idle(&self, ttl: Duration) -> bool {
+        self.last_used.lock().unwrap().elapsed() >= ttl
+            && self.available_permits() == self.max_concurrent()
+            && self.queued() == 0
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent.load(Ordering::Relaxed)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> ModelSummary {
+        self.info.summary(self.max_concurrent())
+    }
+
+    /// Follows changed default limits. Shrinking takes idle permits away
+    /// at once and the rest as running requests release them.
+    fn apply_limits(&self, limits: &LimitConfig) {
+        self.queue_depth.store(
+            self.own_queue_depth.unwrap_or(limits.queue_depth),
+            Ordering::Relaxed,
+        );
+        let target = self.own_max_concurrent.unwrap_or(limits.max_concurrent);
+        let current = self.max_concurrent.swap(target, Ordering::Relaxed);
+        if target > current {
+            let raise = target - current;
+            let cancelled = take_up_to(&self.retiring, raise);
+            self.semaphore.add_permits(raise - cancelled);
+        } else if target < current {
+            let excess = current - target;
+            let outstanding = excess - self.semaphore.forget_permits(excess);
+            if outstanding > 0 && self.retiring.fetch_add(outstanding, Ordering::AcqRel) == 0 {
+                tokio::spawn(retire_permits(
+                    self.semaphore.clone(),
+                    self.retiring.clone(),
+                ));
+            }
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> ModelStatus {
+        ModelStatus {
+            name: self.info.name.clone(),
+            backend: self.info.backend.clone(),
+            max_concurrent: self.max_concurrent(),
+            available_permits: self.available_permits(),
+            queued: self.queued(),
+            last_error: self.last_error.lock().unwrap().clone(),
+            circuit: self.breaker.state(),
+        }
+    }
+
+    pub fn capacity(&self) -> ModelCapacity {
+        let available_permits = self.available_permits();
+        let queue_slots = match self.info.overflow {
+            OverflowBehavior::Queue => self.queue_depth().saturating_sub(self.queued()),
+            _ => 0,
+        };
+        let circuit = self.breaker.state();
+        ModelCapacity {
+            name: self.info.name.clone(),
+            available_permits,
+            queue_slots,
+            circuit,
+            accepting: circuit != CircuitState::Open && (available_permits > 0 || queue_slots > 0),
+        }
+    }
+
+    /// Takes `n` of the model's permits at once.
+    async fn acquire(&self, n: usize) -> Result<OwnedSemaphorePermit, ModelError> {
+        let count = n as u32;
+        if let Ok(permit) = self.semaphore.clone().try_acquire_many_owned(count) {
+            return Ok(permit);
+        }
+        match self.info.overflow {
+            OverflowBehavior::Reject => Err(ModelError::Overloaded),
+            OverflowBehavior::Queue => {
+                let _slot = QueueSlot::take(&self.queued, self.queue_depth())?;
+                self.semaphore
+                    .clone()
+                    .acquire_many_owned(count)
+                    .await
+                    .map_err(|_| ModelError::Overloaded)
+            }
+            OverflowBehavior::ShedOldest => {
+                let free = self.semaphore.available_permits();
+                for _ in free..n {
+                    if !self.inflight.cancel_oldest() {
+                        return Err(ModelError::Overloaded);
+                    }
+                }
+                // The shed stream frees its permit once its consumer sees
+                // the cancellation; don't wait forever if it never does.
+                match tokio::time::timeout(
+                    SHED_WAIT_TIMEOUT,
+                    self.semaphore.clone().acquire_many_owned(count),
+                )
+                .await
+                {
+                    Ok(Ok(permit)) => Ok(permit),
+                    _ => Err(ModelError::Overloaded),
+                }
+            }
+        }
+    }
+
+    /// Takes `n` device or global permits once the model permits are held.
+    /// Other models' requests can't be shed, so `shed_oldest` waits like
+    /// `queue` here.
+    async fn acquire_shared(
+        &self,
+        shared: Option<&Arc<Semaphore>>,
+        n: usize,
+    ) -> Result<Option<OwnedSemaphorePermit>, ModelError> {
+        let Some(shared) = shared else {
+            return Ok(None);
+        };
+        if let Ok(permit) = shared.clone().try_acquire_many_owned(n as u32) {
+            return Ok(Some(permit));
+        }
+        match self.info.overflow {
+            OverflowBehavior::Reject => Err(ModelError::Overloaded),
+            OverflowBehavior::Queue | OverflowBehavior::ShedOldest => {
+                let _slot = QueueSlot::take(&self.queued, self.queue_depth())?;
+                shared
+                    .clone()
+                    .acquire_many_owned(n as u32)
+                    .await
+                    .map(Some)
+                    .map_err(|_| ModelError::Overloaded)
+            }
+        }
+    }
+}
+
+/// Clears a model's loading flag once its load attempt ends.
+struct LoadingGuard {
+    loading: Arc<DashSet<String>>,
+    name: String,
+}
+
+impl Drop for LoadingGuard {
+    fn drop(&mut self) {
+        self.loading.remove(&self.name);
+    }
+}
+
+/// Reserves one of a bounded number of waiter slots; released on drop.
+struct QueueSlot(Arc<AtomicUsize>);
+
+impl QueueSlot {
+    fn take(queued: &Arc<AtomicUsize>, depth: usize) -> Result<Self, ModelError> {
+        if queued.fetch_add(1, Ordering::AcqRel) >= depth {
+            queued.fetch_sub(1, Ordering::AcqRel);
+            return Err(ModelError::Overloaded);
+        }
+        Ok(Self(queued.clone()))
+    }
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// In-flight generations on a handle, oldest first.
+#[derive(Default)]
+struct InflightRegistry {
+    next_id: AtomicU64,
+    entries: Mutex<Vec<(u64, CancellationToken)>>,
+}
+
+impl InflightRegistry {
+    fn register(self: &Arc<Self>) -> InflightEntry {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        self.entries.lock().unwrap().push((id, token.clone()));
+        InflightEntry {
+            id,
+            token,
+            registry: Arc::clone(self),
+        }
+    }
+
+    /// Cancels the oldest generation not already cancelled.
+    fn cancel_oldest(&self) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match entries.iter().find(|(_, token)| !token.is_cancelled()) {
+            Some((_, token)) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct InflightEntry {
+    id: u64,
+    token: CancellationToken,
+    registry: Arc<InflightRegistry>,
+}
+
+impl Drop for InflightEntry {
+    fn drop(&mut self) {
+        self.registry
+            .entries
+            .lock()
+            .unwrap()
+            .retain(|(id, _)| *id != self.id);
     }
 }
 
 pub struct ModelManager {
     models: DashMap<String, Arc<ModelHandle>>,
-    limits: LimitConfig,
+    loading: Arc<DashSet<String>>,
+    pinned: DashSet<String>,
+    global: Option<Arc<Semaphore>>,
+    devices: HashMap<String, Arc<Semaphore>>,
+    default_backend: String,
+    circuit_breaker: CircuitBreakerConfig,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: Option<usize>,
+    strict_parsing: bool,
+    backend_url_forward_api_key: bool,
+    /// Permits for in-progress loads, when `max_concurrent_loads` is set.
+    load_slots: Option<Arc<Semaphore>>,
+    /// Replaced as a whole by [`ModelManager::update_limits`].
+    limits: std::sync::RwLock<Arc<LimitConfig>>,
     metrics: Arc<Metrics>,
+    suggestion_distance: usize,
+    token_counter: TokenCounterKind,
 }
 
 impl ModelManager {
     pub fn new(limits: LimitConfig, metrics: Arc<Metrics>) -> Self {
         Self {
             models: DashMap::new(),
-            limits,
+            loading: Arc::new(DashSet::new()),
+            pinned: DashSet::new(),
+            global: limits
+                .global_max_concurrent
+                .map(|n| Arc::new(Semaphore::new(n))),
+            devices: limits
+                .device_max_concurrent
+                .iter()
+                .map(|(device, &n)| (device.clone(), Arc::new(Semaphore::new(n))))
+                .collect(),
+            default_backend: "llm".to_string(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: None,
+            strict_parsing: false,
+            backend_url_forward_api_key: false,
+            load_slots: None,
+            limits: std::sync::RwLock::new(Arc::new(limits)),
             metrics,
+            suggestion_distance: 0,
+            token_counter: TokenCounterKind::default(),
+        }
+    }
+
+    pub fn with_suggestion_distance(mut self, distance: usize) -> Self {
+        self.suggestion_distance = distance;
+        self
+    }
+
+    /// Sets the backend of models that don't name one; it must be one of
+    /// [`KNOWN_BACKENDS`].
+    pub fn with_default_backend(mut self, backend: String) -> Result<Self, ModelError> {
+        if !KNOWN_BACKENDS.contains(&backend.as_str()) {
+            return Err(ModelError::InvalidConfig(format!(
+                "unknown default_backend '{backend}', expected one of: {}",
+                KNOWN_BACKENDS.join(", ")
+            )));
         }
+        self.default_backend = backend;
+        Ok(self)
+    }
+
+    pub fn with_circuit_breaker(mut self, cfg: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = cfg;
+        self
+    }
+
+    /// Keep-alive settings for the connection pools of backend clients.
+    pub fn with_pool(mut self, idle_timeout: Duration, max_idle_per_host: Option<usize>) -> Self {
+        self.pool_idle_timeout = idle_timeout;
+        self.pool_max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    /// End a stream with an error on a backend event that doesn't match the
+    /// expected chunk format, instead of forwarding it as text.
+    pub fn with_strict_parsing(mut self, strict: bool) -> Self {
+        self.strict_parsing = strict;
+        self
+    }
+
+    /// Authenticate requests sent to a `backend_url` override with the
+    /// model's API key.
+    pub fn with_backend_url_forward_api_key(mut self, forward: bool) -> Self {
+        self.backend_url_forward_api_key = forward;
+        self
+    }
+
+    pub fn with_max_concurrent_loads(mut self, max: Option<usize>) -> Self {
+        self.load_slots = max.map(|n| Arc::new(Semaphore::new(n)));
+        self
     }
 
-    pub async fn load_model(&self, cfg: ModelConfig) -> Result<ModelSummary, ModelError> {
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder().pool_idle_timeout(self.pool_idle_timeout);
+        match self.pool_max_idle_per_host {
+            Some(max) => builder.pool_max_idle_per_host(max),
+            None => builder,
+        }
+    }
+
+    pub fn with_token_counter(mut self, kind: TokenCounterKind) -> Self {
+        self.token_counter = kind;
+        self
+    }
+
+    /// Exempts a model from idle eviction until it is unloaded.
+    pub fn pin(&self, name: &str) {
+        self.pinned.insert(name.to_string());
+    }
+
+    /// Unloads every unpinned model idle for longer than `ttl`, returning the
+    /// names evicted.
+    pub async fn evict_idle(&self, ttl: Duration) -> Vec<String> {
+        let idle: Vec<String> = self
+            .models
+            .iter()
+            .filter(|entry| !self.pinned.contains(entry.key()) && entry.is_idle(ttl))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut evicted = Vec::new();
+        for name in idle {
+            // A request may have arrived since the scan; only remove the
+            // model if it is still idle.
+            let Some((_, handle)) = self.models.remove_if(&name, |_, h| h.is_idle(ttl)) else {
+                continue;
+            };
+            handle.unload_backend().await;
+            self.metrics.set_models_loaded(self.models.len() as u64);
+            self.metrics.remove_cold_start(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+
+    /// Marks a model as loading so requests for it get a 503 rather than a
+    /// 404 until `load_model` finishes with it.
+    pub fn mark_loading(&self, name: &str) {
+        self.loading.insert(name.to_string());
+    }
+
+    /// Loads and registers a model. A model of the same name is an error
+    /// unless `replace` is set, in which case the old handle is swapped out
+    /// (its in-flight requests run to completion) and its backend unloaded.
+    pub async fn load_model(
+        &self,
+        cfg: ModelConfig,
+        replace: bool,
+    ) -> Result<ModelSummary, ModelError> {
+        self.load_model_reporting(cfg, replace, None).await
+    }
+
+    /// [`Self::load_model`], sending each stage to `progress`. A client
+    /// following the load also waits for the backend to finish warming up.
+    pub async fn load_model_reporting(
+        &self,
+        cfg: ModelConfig,
+        replace: bool,
+        progress: Option<LoadProgress>,
+    ) -> Result<ModelSummary, ModelError> {
+        self.mark_loading(&cfg.name);
+        let _loading = LoadingGuard {
+            loading: self.loading.clone(),
+            name: cfg.name.clone(),
+        };
+        // After the guard: startup marks config models as loading up front,
+        // and a model rejected here must not stay marked.
+        self.validate_config(&cfg, replace)?;
+        // Queued loads already show as loading in the model status.
+        let _slot = match &self.load_slots {
+            Some(slots) => Some(
+                slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| ModelError::Backend("load queue closed".to_string()))?,
+            ),
+            None => None,
+        };
         let backend_choice = cfg
             .backend
             .clone()
-            .unwrap_or_else(|| "llm".to_string());
+            .unwrap_or_else(|| self.default_backend.clone());
 
+        let client = backend_client(&cfg, self.client_builder())?;
+        report(&progress, LoadStage::Connecting);
+        let backend_choice = if backend_choice == "auto" {
+            let health_path = cfg.health_path.as_deref().unwrap_or(DEFAULT_HEALTH_PATH);
+            let detected = detect_backend(&client, &primary_url(&cfg), health_path).await?;
+            tracing::info!(model = %cfg.name, backend = detected, "detected backend");
+            detected.to_string()
+        } else {
+            backend_choice
+        };
+        let anonymous = self
+            .client_builder()
+            .build()
+            .map_err(|err| ModelError::Backend(err.to_string()))?;
+        // Ollama, TGI and vLLM all serve the OpenAI-compatible chat endpoint
+        // the llama-server client speaks.
         let backend: Arc<dyn ModelBackend> = match backend_choice.as_str() {
-            "llm" | "llama-server" => Arc::new(
-                LlamaServerBackend::new(cfg.clone())
-                    .map_err(|e| ModelError::Backend(e.to_string()))?,
+            "llm" | "llama-server" | "ollama" | "tgi" | "vllm" => Arc::new(
+                LlamaServerBackend::new(cfg.clone(), client.clone(), anonymous)
+                    .map_err(|e| ModelError::Backend(e.to_string()))?
+                    .with_strict_parsing(self.strict_parsing)
+                    .with_backend_url_forward_api_key(self.backend_url_forward_api_key),
             ),
             other => {
                 return Err(ModelError::Backend(format!(
-                    "unsupported backend '{}', use 'llama-server'",
-                    other
-                )))
+                "unsupported backend '{}', use 'llama-server', 'ollama', 'tgi', 'vllm' or 'auto'",
+                other
+            )))
             }
         };
-        backend.load(&cfg).await?;
+        let template = match &cfg.template_path {
+            Some(path) => Some(Arc::new(ChatTemplate::from_file(path)?)),
+            None => None,
+        };
+        backend.load(&cfg, progress.as_ref()).await?;
+        let counter = self.token_counter_for(&cfg, client);
 
-        let max_concurrent = cfg.max_concurrent.unwrap_or(self.limits.max_concurrent);
+        let limits = self.limits();
+        let max_concurrent = cfg.max_concurrent.unwrap_or(limits.max_concurrent);
+        let context_length = cfg.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH);
+        // llama-server streams completions, and tool calls only when started
+        // with --jinja, but we expose no embeddings endpoint for it.
+        let capabilities = ModelCapabilities {
+            streaming: true,
+            supports_embeddings: false,
+            supports_tools: cfg
+                .supports_tools
+                .unwrap_or(!matches!(backend_choice.as_str(), "llm" | "llama-server")),
+            supports_prompt_logprobs: backend_choice == "vllm",
+            context_length,
+            max_tokens: limits
+                .token_cap()
+                .map_or(context_length, |cap| cap.min(context_length)),
+        };
 
         let info = ModelInfo {
             name: cfg.name.clone(),
             device: cfg.device.unwrap_or_else(|| "cpu".to_string()),
             backend: backend_choice,
             quantization: cfg.quantization.clone(),
-            max_concurrent,
+            overflow: cfg.overflow_behavior,
+            capabilities,
         };
 
         let handle = Arc::new(ModelHandle {
-            info: info.clone(),
+            device: self.devices.get(&info.device).cloned(),
+            info,
             backend,
+            counter,
             semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            global: self.global.clone(),
+            max_concurrent: AtomicUsize::new(max_concurrent),
+            retiring: Arc::new(AtomicUsize::new(0)),
+            queue_depth: AtomicUsize::new(cfg.queue_depth.unwrap_or(limits.queue_depth)),
+            own_max_concurrent: cfg.max_concurrent,
+            own_queue_depth: cfg.queue_depth,
+            queued: Arc::new(AtomicUsize::new(0)),
+            inflight: Arc::new(InflightRegistry::default()),
+            last_used: Mutex::new(Instant::now()),
+            last_error: Arc::new(Mutex::new(None)),
+            breaker: CircuitBreaker::new(
+                self.circuit_breaker.failure_threshold,
+                Duration::from_secs(self.circuit_breaker.cooldown_seconds),
+            ),
+            safety: cfg.safety,
+            template,
+            request_timeout: cfg.request_timeout_seconds.map(Duration::from_secs),
+            prompt_prefix: cfg.prompt_prefix,
+            prompt_suffix: cfg.prompt_suffix,
+            arch: cfg.arch,
+            metrics: self.metrics.clone(),
+            cold: AtomicBool::new(true),
+            rate_limit: cfg.rate_limit_rpm.map(TokenBucket::per_minute),
         });
 
-        self.models.insert(cfg.name.clone(), handle);
-        self.metrics
-            .set_models_loaded(self.models.len() as u64);
+        let summary = handle.summary();
+        // Another load of the same name may have finished while this one ran.
+        let displaced = match self.models.entry(cfg.name.clone()) {
+            Entry::Occupied(_) if !replace => Err(handle),
+            Entry::Occupied(mut entry) => Ok(Some(entry.insert(handle))),
+            Entry::Vacant(entry) => {
+                entry.insert(handle);
+                Ok(None)
+            }
+        };
+        match displaced {
+            Err(handle) => {
+                handle.unload_backend().await;
+                return Err(ModelError::AlreadyLoaded(cfg.name));
+            }
+            Ok(Some(previous)) => previous.unload_backend().await,
+            Ok(None) => {}
+        }
+        self.metrics.set_models_loaded(self.models.len() as u64);
+
+        report(
+            &progress,
+            LoadStage::Ready {
+                model: summary.clone(),
+            },
+        );
+        Ok(summary)
+    }
 
-        Ok(ModelSummary {
-            name: info.name,
-            device: info.device,
-            backend: info.backend,
-            quantization: info.quantization,
-            max_concurrent: info.max_concurrent,
-        })
+    /// Checks what can be checked about `cfg` without contacting its
+    /// backend, so a followed load can fail before its stream starts.
+    pub fn validate_config(&self, cfg: &ModelConfig, replace: bool) -> Result<(), ModelError> {
+        if !replace && self.models.contains_key(&cfg.name) {
+            return Err(ModelError::AlreadyLoaded(cfg.name.clone()));
+        }
+        if let Some(device) = &cfg.device {
+            validate_device(device)?;
+        }
+        let backend = cfg.backend.as_deref().unwrap_or(&self.default_backend);
+        if !KNOWN_BACKENDS.contains(&backend) {
+            return Err(ModelError::InvalidConfig(format!(
+                "unknown backend '{backend}', expected one of: {}",
+                KNOWN_BACKENDS.join(", ")
+            )));
+        }
+        if let Some(path) = &cfg.template_path {
+            ChatTemplate::from_file(path)?;
+        }
+        Ok(())
     }
 
     pub async fn unload_model(&self, name: &str) -> Result<(), ModelError> {
         if let Some((_, handle)) = self.models.remove(name) {
+            self.pinned.remove(name);
+            self.metrics.remove_cold_start(name);
             handle.backend.unload().await?;
-            self.metrics
-                .set_models_loaded(self.models.len() as u64);
+            self.metrics.set_models_loaded(self.models.len() as u64);
             Ok(())
         } else {
-            Err(ModelError::NotFound(name.to_string()))
+            Err(self.not_found(name))
         }
     }
 
+    /// Loaded models ordered by name.
     pub fn list_models(&self) -> Vec<ModelSummary> {
+        let mut models: Vec<ModelSummary> =
+            self.models.iter().map(|entry| entry.summary()).collect();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        models
+    }
+
+    pub fn status(&self) -> Vec<ModelStatus> {
+        let mut status: Vec<ModelStatus> = self.models.iter().map(|entry| entry.status()).collect();
+        status.sort_by(|a, b| a.name.cmp(&b.name));
+        status
+    }
+
+    /// Per-model capacity ordered by name.
+    pub fn capacity(&self) -> Vec<ModelCapacity> {
+        let mut capacity: Vec<ModelCapacity> =
+            self.models.iter().map(|entry| entry.capacity()).collect();
+        capacity.sort_by(|a, b| a.name.cmp(&b.name));
+        capacity
+    }
+
+    /// The limits currently in force.
+    pub fn limits(&self) -> Arc<LimitConfig> {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// Changes limits at runtime. Loaded models that don't set their own
+    /// `max_concurrent`/`queue_depth` are resized to the new defaults.
+    /// Limits that fail [`LimitConfig::validate`] leave everything as it was.
+    pub fn update_limits(
+        &self,
+        update: impl FnOnce(&mut LimitConfig),
+    ) -> Result<Arc<LimitConfig>, ModelError> {
+        let mut current = self.limits.write().unwrap();
+        let mut limits = LimitConfig::clone(&current);
+        update(&mut limits);
+        limits.validate().map_err(ModelError::InvalidConfig)?;
+        let limits = Arc::new(limits);
+        for entry in self.models.iter() {
+            entry.apply_limits(&limits);
+        }
+        *current = limits.clone();
+        Ok(limits)
+    }
+
+    /// Free permits under `limits.global_max_concurrent`, if set.
+    pub fn global_available(&self) -> Option<usize> {
+        self.global
+            .as_ref()
+            .map(|global| global.available_permits())
+    }
+
+    pub fn loading(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.loading.iter().map(|name| name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    fn token_counter_for(
+        &self,
+        cfg: &ModelConfig,
+        client: reqwest::Client,
+    ) -> Arc<dyn TokenCounter> {
+        match self.token_counter {
+            TokenCounterKind::Heuristic => Arc::new(HeuristicCounter),
+            TokenCounterKind::Whitespace => Arc::new(WhitespaceCounter),
+            TokenCounterKind::Backend => Arc::new(BackendCounter::new(client, &primary_url(cfg))),
+        }
+    }
+
+    /// Counts tokens in `text` with the model's configured counter, or the
+    /// heuristic when the model is not loaded.
+    pub async fn count_tokens(&self, model: &str, text: &str) -> usize {
+        let counter = self.models.get(model).map(|entry| entry.counter.clone());
+        match counter {
+            Some(counter) => counter.count(text).await,
+            None => HeuristicCounter.count(text).await,
+        }
+    }
+
+    /// Renders chat `messages` through the model's custom template; `None`
+    /// when the model has none and the default prompt format applies.
+    pub fn render_chat<M: Serialize>(
+        &self,
+        model: &str,
+        messages: &[M],
+    ) -> Option<Result<String, ModelError>> {
+        let template = self.models.get(model)?.template.clone()?;
+        Some(template.render(messages))
+    }
+
+    /// Surrounds a fully rendered prompt with the model's configured
+    /// prefix and suffix.
+    pub fn wrap_prompt(&self, model: &str, prompt: String) -> String {
+        let Some(entry) = self.models.get(model) else {
+            return prompt;
+        };
+        match (&entry.prompt_prefix, &entry.prompt_suffix) {
+            (None, None) => prompt,
+            (prefix, suffix) => format!(
+                "{}{prompt}{}",
+                prefix.as_deref().unwrap_or_default(),
+                suffix.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    /// A fill-in-the-middle prompt in the model's format, or `None` when its
+    /// `arch` has no FIM support.
+    pub fn fim_prompt(
+        &self,
+        model: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<Option<String>, ModelError> {
+        let entry = self
+            .models
+            .get(model)
+            .ok_or_else(|| self.not_found(model))?;
+        Ok(entry
+            .arch
+            .as_deref()
+            .and_then(|arch| fim_prompt(arch, prefix, suffix)))
+    }
+
+    pub fn capabilities(&self, model: &str) -> Result<ModelCapabilities, ModelError> {
+        let entry = self
+            .models
+            .get(model)
+            .ok_or_else(|| self.not_found(model))?;
+        Ok(entry.info.capabilities.clone())
+    }
+
+    /// The model's own request timeout, if it overrides the global one.
+    pub fn request_timeout(&self, name: &str) -> Option<Duration> {
         self.models
-            .iter()
-            .map(|entry| ModelSummary {
-                name: entry.info.name.clone(),
-                device: entry.info.device.clone(),
-                backend: entry.info.backend.clone(),
-                quantization: entry.info.quantization.clone(),
-                max_concurrent: entry.info.max_concurrent,
-            })
-            .collect()
+            .get(name)
+            .and_then(|entry| entry.request_timeout)
+    }
+
+    /// The model's own safety settings, if it overrides the global ones.
+    pub fn safety_override(&self, name: &str) -> Option<SafetyConfig> {
+        self.models.get(name).and_then(|entry| entry.safety.clone())
+    }
+
+    pub fn model_status(&self, name: &str) -> Option<ModelStatus> {
+        self.models.get(name).map(|entry| entry.status())
+    }
+
+    /// Takes a token from the model's rate limit for one client request;
+    /// internal generations such as readiness probes don't count.
+    pub fn take_rate_limit(&self, model: &str) -> Result<(), ModelError> {
+        let handle = self
+            .models
+            .get(model)
+            .ok_or_else(|| self.not_found(model))?;
+        handle.take_rate_limit()
     }
 
     pub async fn stream(
@@ -187,60 +1240,579 @@ impl ModelManager {
         let handle = self
             .models
             .get(model)
-            .ok_or_else(|| ModelError::NotFound(model.to_string()))?;
+            .ok_or_else(|| self.not_found(model))?;
         handle.stream(params).await
     }
+
+    /// `n` generations of `params`, started together; see
+    /// [`ModelHandle::streams`].
+    pub async fn streams(
+        &self,
+        model: &str,
+        params: GenerateParams,
+        n: usize,
+    ) -> Result<Vec<ModelStream>, ModelError> {
+        let handle = self
+            .models
+            .get(model)
+            .ok_or_else(|| self.not_found(model))?;
+        handle.streams(params, n).await
+    }
+
+    /// Returns the loaded model name closest to `name` by edit distance, if
+    /// any is within the configured suggestion distance.
+    pub fn suggest_model(&self, name: &str) -> Option<String> {
+        if self.suggestion_distance == 0 {
+            return None;
+        }
+        self.models
+            .iter()
+            .map(|entry| (edit_distance(name, entry.key()), entry.key().clone()))
+            .filter(|(distance, _)| *distance <= self.suggestion_distance)
+            .min()
+            .map(|(_, candidate)| candidate)
+    }
+
+    fn not_found(&self, name: &str) -> ModelError {
+        if self.loading.contains(name) {
+            return ModelError::Loading(name.to_string());
+        }
+        match self.suggest_model(name) {
+            Some(candidate) => {
+                ModelError::NotFound(format!("{name} (did you mean '{candidate}'?)"))
+            }
+            None => ModelError::NotFound(name.to_string()),
+        }
+    }
 }
 
+/// Levenshtein distance over chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Holds the model permit for the lifetime of a generation and ends the
+/// stream early if the generation is cancelled, with a final event whose
+/// `finish_reason` is `"cancelled"`.
 pub struct GuardedStream<S> {
     inner: S,
+    /// Set once a finishing event has been yielded.
+    done: bool,
+    cancelled: Pin<Box<WaitForCancellationFutureOwned>>,
     _permit: OwnedSemaphorePermit,
+    /// Device and global permits, when those limits apply.
+    _shared: Vec<OwnedSemaphorePermit>,
+    _entry: InflightEntry,
 }
 
 impl<S> GuardedStream<S> {
-    pub fn new(inner: S, permit: OwnedSemaphorePermit) -> Self {
-        Self { inner, _permit: permit }
+    pub fn new(
+        inner: S,
+        permit: OwnedSemaphorePermit,
+        shared: Vec<OwnedSemaphorePermit>,
+        entry: InflightEntry,
+    ) -> Self {
+        Self {
+            inner,
+            done: false,
+            cancelled: Box::pin(entry.token.clone().cancelled_owned()),
+            _permit: permit,
+            _shared: shared,
+            _entry: entry,
+        }
     }
 }
 
-impl<S: Stream + Unpin> Stream for GuardedStream<S> {
-    type Item = S::Item;
+impl<S: Stream<Item = TokenEvent> + Unpin> Stream for GuardedStream<S> {
+    type Item = TokenEvent;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let inner = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
-        inner.poll_next(cx)
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        if this.cancelled.as_mut().poll(cx).is_ready() {
+            this.done = true;
+            return Poll::Ready(Some(TokenEvent {
+                token: String::new(),
+                finished: true,
+                progress: None,
+                logprob: None,
+                tool_calls: None,
+                finish_reason: Some("cancelled".to_string()),
+                prompt_logprobs: None,
+                malformed: false,
+            }));
+        }
+        let next = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(event)) = &next {
+            this.done = event.finished;
+        }
+        next
+    }
+}
+
+fn report(progress: &Option<LoadProgress>, stage: LoadStage) {
+    if let Some(progress) = progress {
+        let _ = progress.try_send(stage);
     }
 }
 
+/// Backend names accepted in `ModelConfig::backend`.
+pub const KNOWN_BACKENDS: &[&str] = &["llm", "llama-server", "ollama", "tgi", "vllm", "auto"];
+
+const DEFAULT_SERVER_URL: &str = "http://127.0.0.1:8081";
+
+/// llama-server's readiness endpoint, probed unless a model sets its own.
+const DEFAULT_HEALTH_PATH: &str = "/health";
+
+/// How long each endpoint probe of `backend = "auto"` may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often, and by default for how long, a followed load polls a backend
+/// that is still loading its weights.
+const WARM_UP_POLL: Duration = Duration::from_millis(500);
+const DEFAULT_WARM_UP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A mirrored request still running after this long is abandoned.
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long the rest of a finished stream is read for so its connection
+/// can go back to the pool.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The server a model's requests go to first.
+fn primary_url(cfg: &ModelConfig) -> String {
+    cfg.replicas
+        .first()
+        .map(|replica| replica.url.clone())
+        .or_else(|| cfg.server_url.clone())
+        .unwrap_or_else(|| DEFAULT_SERVER_URL.to_string())
+}
+
+const DEFAULT_CONTEXT_LENGTH: usize = 2048;
+
+/// Request body keys set by the typed parameters; `extra` may not use them.
+pub const RESERVED_PARAMS: &[&str] = &[
+    "model",
+    "messages",
+    "prompt",
+    "temperature",
+    "top_p",
+    "presence_penalty",
+    "frequency_penalty",
+    "max_tokens",
+    "stream",
+    "stop",
+    "cache_prompt",
+    "logit_bias",
+    "return_progress",
+    "logprobs",
+    "tools",
+    "tool_choice",
+    "seed",
+    "prompt_logprobs",
+];
+
+/// Works out which API the server at `base` speaks: Ollama (`/api/tags`),
+/// TGI (`/info` naming a `model_id`), vLLM (`/v1/models` listing models
+/// `owned_by` `"vllm"`) or plain OpenAI-compatible (`/v1/models`). The
+/// specific APIs are tried first because Ollama and TGI answer `/v1/models`
+/// as well. A server that doesn't answer
+/// `health_path` at all fails fast instead of timing out on every probe.
+async fn detect_backend(
+    client: &reqwest::Client,
+    base: &str,
+    health_path: &str,
+) -> Result<&'static str, ModelError> {
+    let reachable = client
+        .get(format!("{base}{health_path}"))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await;
+    if let Err(err) = reachable {
+        return Err(ModelError::Backend(format!(
+            "backend at {base} is unreachable: {err}"
+        )));
+    }
+    let probe = |path: &str| {
+        let request = client.get(format!("{base}{path}")).timeout(PROBE_TIMEOUT);
+        async move {
+            request
+                .send()
+                .await
+                .ok()
+                .filter(|resp| resp.status().is_success())
+        }
+    };
+    if probe("/api/tags").await.is_some() {
+        return Ok("ollama");
+    }
+    if let Some(resp) = probe("/info").await {
+        if let Ok(info) = resp.json::<Value>().await {
+            if info.get("model_id").is_some() {
+                return Ok("tgi");
+            }
+        }
+    }
+    if let Some(resp) = probe("/v1/models").await {
+        let models = resp.json::<Value>().await.unwrap_or_default();
+        let vllm = models["data"]
+            .as_array()
+            .is_some_and(|data| data.iter().any(|model| model["owned_by"] == "vllm"));
+        return Ok(if vllm { "vllm" } else { "llama-server" });
+    }
+    Err(ModelError::Backend(format!(
+        "could not detect the API served at {base}; set backend explicitly"
+    )))
+}
+
+/// Drops the first `skip` bytes of a reconnected stream's replayed text,
+/// counting `skip` down. A cut inside a character moves to the next
+/// boundary.
+fn skip_replayed_text(skip: &mut usize, text: String) -> String {
+    if text.len() <= *skip {
+        *skip -= text.len();
+        return String::new();
+    }
+    let start = (*skip..=text.len())
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(text.len());
+    *skip = 0;
+    text[start..].to_string()
+}
+
+/// Drops the first `skip` replayed tool-call fragments, counting `skip` down.
+fn skip_replayed_calls(
+    skip: &mut usize,
+    calls: Option<Vec<ToolCallDelta>>,
+) -> Option<Vec<ToolCallDelta>> {
+    let mut calls = calls?;
+    let dropped = calls.len().min(*skip);
+    *skip -= dropped;
+    calls.drain(..dropped);
+    (!calls.is_empty()).then_some(calls)
+}
+
+/// Reads what is left of a finished backend stream. The pool only takes a
+/// connection back once its response has been read to the end.
+async fn drain(stream: impl Stream + Unpin) {
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, stream.count()).await;
+}
+
+/// Checks a streamed event against the OpenAI chat chunk format, which
+/// llama-server, Ollama and TGI all speak on `/v1/chat/completions`, plus
+/// llama.cpp's `prompt_progress` events.
+fn validate_chunk(v: &Value) -> Result<(), String> {
+    let Some(obj) = v.as_object() else {
+        return Err("event is not a JSON object".to_string());
+    };
+    if let Some(err) = obj.get("error") {
+        return Err(format!("backend reported an error: {err}"));
+    }
+    if obj.contains_key("prompt_progress") {
+        return Ok(());
+    }
+    let Some(choices) = obj.get("choices").and_then(Value::as_array) else {
+        return Err("missing 'choices' array".to_string());
+    };
+    for choice in choices {
+        let Some(delta) = choice.get("delta") else {
+            return Err("choice without a 'delta'".to_string());
+        };
+        if !delta.is_object() {
+            return Err("'delta' is not an object".to_string());
+        }
+        if delta
+            .get("content")
+            .is_some_and(|c| !(c.is_string() || c.is_null()))
+        {
+            return Err("'delta.content' is not a string".to_string());
+        }
+        if delta
+            .get("tool_calls")
+            .is_some_and(|t| !(t.is_array() || t.is_null()))
+        {
+            return Err("'delta.tool_calls' is not an array".to_string());
+        }
+        if choice
+            .get("finish_reason")
+            .is_some_and(|f| !(f.is_string() || f.is_null()))
+        {
+            return Err("'finish_reason' is not a string".to_string());
+        }
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct LlamaServerBackend {
     model_name: String,
-    server_url: String,
+    /// Replica URLs, each repeated by its weight.
+    schedule: Arc<Vec<String>>,
+    next: Arc<AtomicUsize>,
     client: reqwest::Client,
+    /// Same pool settings as `client` but without the model's API key, for
+    /// servers that must not see it.
+    anonymous_client: reqwest::Client,
     max_context: usize,
+    shadow_url: Option<String>,
+    shadow_percent: f64,
+    strict_parsing: bool,
+    backend_url_forward_api_key: bool,
+    health_path: String,
+}
+
+/// Checks `device` is `cpu`, `metal`, `vulkan` or `cuda:N`. llama-server
+/// places the model itself, so the value is only advisory metadata, but a
+/// typo like `gpu` is still worth catching.
+fn validate_device(device: &str) -> Result<(), ModelError> {
+    let valid = match device.strip_prefix("cuda:") {
+        Some(index) => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+        None => matches!(device, "cpu" | "metal" | "vulkan"),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ModelError::InvalidConfig(format!(
+            "unknown device '{device}', expected cpu, cuda:N, metal or vulkan"
+        )))
+    }
+}
+
+/// HTTP client for a model's backend, authenticating every request when the
+/// model has an API key configured.
+fn backend_client(
+    cfg: &ModelConfig,
+    builder: reqwest::ClientBuilder,
+) -> Result<reqwest::Client, ModelError> {
+    let key = match (&cfg.api_key, &cfg.api_key_env) {
+        (Some(key), _) => Some(key.expose().to_string()),
+        (None, Some(var)) => Some(std::env::var(var).map_err(|_| {
+            ModelError::Backend(format!(
+                "api_key_env '{var}' for model '{}' is not set",
+                cfg.name
+            ))
+        })?),
+        (None, None) => None,
+    };
+    let Some(key) = key else {
+        return builder
+            .build()
+            .map_err(|err| ModelError::Backend(err.to_string()));
+    };
+    let mut auth =
+        reqwest::header::HeaderValue::from_str(&format!("Bearer {key}")).map_err(|_| {
+            ModelError::Backend(format!(
+                "api key for model '{}' is not a valid header",
+                cfg.name
+            ))
+        })?;
+    auth.set_sensitive(true);
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::AUTHORIZATION, auth);
+    builder
+        .default_headers(headers)
+        .build()
+        .map_err(|err| ModelError::Backend(err.to_string()))
 }
 
 impl LlamaServerBackend {
-    pub fn new(cfg: ModelConfig) -> anyhow::Result<Self> {
+    pub fn new(
+        cfg: ModelConfig,
+        client: reqwest::Client,
+        anonymous_client: reqwest::Client,
+    ) -> anyhow::Result<Self> {
         let server_url = cfg
             .server_url
-            .unwrap_or_else(|| "http://127.0.0.1:8081".to_string());
+            .unwrap_or_else(|| DEFAULT_SERVER_URL.to_string());
+        let mut schedule: Vec<String> = cfg
+            .replicas
+            .iter()
+            .flat_map(|r| std::iter::repeat_n(r.url.clone(), r.weight as usize))
+            .collect();
+        if schedule.is_empty() {
+            schedule.push(server_url);
+        }
+        if !(0.0..=100.0).contains(&cfg.shadow_percent) {
+            anyhow::bail!("shadow_percent must be between 0 and 100");
+        }
+        let health_path = cfg
+            .health_path
+            .unwrap_or_else(|| DEFAULT_HEALTH_PATH.to_string());
+        if !health_path.starts_with('/') {
+            anyhow::bail!("health_path must start with '/', got '{health_path}'");
+        }
         Ok(Self {
             model_name: cfg.name,
-            server_url,
-            client: reqwest::Client::new(),
-            max_context: cfg.context_length.unwrap_or(2048),
+            schedule: Arc::new(schedule),
+            next: Arc::new(AtomicUsize::new(0)),
+            client,
+            anonymous_client,
+            max_context: cfg.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH),
+            shadow_url: cfg.shadow_url,
+            shadow_percent: cfg.shadow_percent,
+            strict_parsing: false,
+            backend_url_forward_api_key: false,
+            health_path,
         })
     }
+
+    pub fn with_strict_parsing(mut self, strict: bool) -> Self {
+        self.strict_parsing = strict;
+        self
+    }
+
+    pub fn with_backend_url_forward_api_key(mut self, forward: bool) -> Self {
+        self.backend_url_forward_api_key = forward;
+        self
+    }
+
+    /// Sends a copy of `body` to the shadow backend, if this request is
+    /// sampled, without waiting for it: the response is drained and logged
+    /// for comparison, never returned to the client.
+    fn mirror<B: serde::Serialize>(&self, body: &B, request_id: &str) {
+        let Some(shadow_url) = &self.shadow_url else {
+            return;
+        };
+        if rand::random::<f64>() * 100.0 >= self.shadow_percent {
+            return;
+        }
+        let Ok(body) = serde_json::to_value(body) else {
+            return;
+        };
+        let url = format!("{shadow_url}/v1/chat/completions");
+        // The shadow is a different server, so it never gets the model's key.
+        let client = self.anonymous_client.clone();
+        let model = self.model_name.clone();
+        let request_id = request_id.to_string();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let request = async {
+                let resp = client.post(url).json(&body).send().await?;
+                let status = resp.status();
+                resp.bytes().await.map(|bytes| (status, bytes.len()))
+            };
+            match tokio::time::timeout(SHADOW_TIMEOUT, request).await {
+                Ok(Ok((status, bytes))) => tracing::info!(
+                    model = %model,
+                    request_id = %request_id,
+                    status = status.as_u16(),
+                    bytes,
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "shadow request completed"
+                ),
+                Ok(Err(err)) => tracing::warn!(
+                    model = %model,
+                    request_id = %request_id,
+                    error = %err,
+                    "shadow request failed"
+                ),
+                Err(_) => tracing::warn!(
+                    model = %model,
+                    request_id = %request_id,
+                    timeout_secs = SHADOW_TIMEOUT.as_secs(),
+                    "shadow request timed out"
+                ),
+            }
+        });
+    }
+
+    /// Weighted round-robin, or a stable hash of the session id so a
+    /// conversation keeps hitting the replica holding its prompt cache.
+    fn pick_server(&self, session_id: Option<&str>) -> &str {
+        let slot = match session_id {
+            Some(id) => {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                id.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            None => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        &self.schedule[slot % self.schedule.len()]
+    }
+
+    /// Each distinct server in the schedule, in first-use order.
+    fn servers(&self) -> Vec<String> {
+        let mut servers: Vec<String> = Vec::new();
+        for url in self.schedule.iter() {
+            if !servers.contains(url) {
+                servers.push(url.clone());
+            }
+        }
+        servers
+    }
+
+    /// Probes `health_path` on each of `servers`, returning those still
+    /// loading their weights (503).
+    async fn still_loading(&self, servers: Vec<String>) -> Vec<String> {
+        let probes = servers.iter().map(|server| {
+            self.client
+                .get(format!("{server}{}", self.health_path))
+                .send()
+        });
+        let responses = futures::future::join_all(probes).await;
+        servers
+            .into_iter()
+            .zip(responses)
+            .filter(|(_, resp)| {
+                resp.as_ref()
+                    .is_ok_and(|resp| resp.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE)
+            })
+            .map(|(server, _)| server)
+            .collect()
+    }
 }
 
 #[async_trait]
 impl ModelBackend for LlamaServerBackend {
-    async fn load(&self, _cfg: &ModelConfig) -> Result<(), ModelError> {
-        // Best-effort check: ensure server is reachable.
-        let url = format!("{}/health", self.server_url);
-        let _ = self.client.get(url).send().await;
-        Ok(())
+    async fn load(
+        &self,
+        cfg: &ModelConfig,
+        progress: Option<&LoadProgress>,
+    ) -> Result<(), ModelError> {
+        // Best-effort check: ensure every server is reachable.
+        let mut servers = self.servers();
+        // llama-server answers 503 until the weights are in memory; only a
+        // client following the load waits that out.
+        let Some(progress) = progress else {
+            self.still_loading(servers).await;
+            return Ok(());
+        };
+        servers = self.still_loading(servers).await;
+        if servers.is_empty() {
+            return Ok(());
+        }
+        let _ = progress.try_send(LoadStage::WarmingUp);
+        let timeout = cfg
+            .warm_up_timeout_seconds
+            .map_or(DEFAULT_WARM_UP_TIMEOUT, Duration::from_secs);
+        let started = Instant::now();
+        loop {
+            tokio::time::sleep(WARM_UP_POLL).await;
+            servers = self.still_loading(servers).await;
+            if servers.is_empty() {
+                return Ok(());
+            }
+            if started.elapsed() >= timeout {
+                return Err(ModelError::Backend(format!(
+                    "model '{}' still loading on {} after {}s",
+                    self.model_name,
+                    servers.join(", "),
+                    timeout.as_secs()
+                )));
+            }
+        }
     }
 
     async fn unload(&self) -> Result<(), ModelError> {
@@ -263,10 +1835,31 @@ impl ModelBackend for LlamaServerBackend {
             messages: Vec<ChatMessage>,
             temperature: f32,
             top_p: f32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            presence_penalty: Option<f32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            frequency_penalty: Option<f32>,
             max_tokens: usize,
             stream: bool,
             #[serde(skip_serializing_if = "Option::is_none")]
             stop: Option<Vec<String>>,
+            cache_prompt: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            logit_bias: Option<HashMap<String, f32>>,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            return_progress: bool,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            logprobs: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tools: Option<Vec<Value>>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tool_choice: Option<Value>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            seed: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            prompt_logprobs: Option<u8>,
+            #[serde(flatten)]
+            extra: serde_json::Map<String, Value>,
         }
 
         let GenerateParams {
@@ -274,8 +1867,23 @@ impl ModelBackend for LlamaServerBackend {
             max_tokens,
             temperature,
             top_p,
+            presence_penalty,
+            frequency_penalty,
             stop,
-            ..
+            cache_prompt,
+            session_id,
+            logit_bias,
+            return_progress,
+            extra,
+            logprobs,
+            request_id,
+            tools,
+            tool_choice,
+            backend_url,
+            seed,
+            reconnects,
+            include_stop,
+            prompt_logprobs,
         } = params;
 
         let n_predict = max_tokens.min(self.max_context);
@@ -287,38 +1895,92 @@ impl ModelBackend for LlamaServerBackend {
             }],
             temperature,
             top_p,
+            presence_penalty,
+            frequency_penalty,
             max_tokens: n_predict,
             stream: true,
-            stop,
+            stop: if include_stop { None } else { stop },
+            cache_prompt,
+            logit_bias,
+            return_progress,
+            logprobs,
+            tools,
+            tool_choice,
+            seed,
+            prompt_logprobs,
+            extra: extra.unwrap_or_default(),
         };
 
-        let url = format!("{}/v1/chat/completions", self.server_url);
-        let client = self.client.clone();
+        let (server, client) = match &backend_url {
+            Some(url) if !self.backend_url_forward_api_key => {
+                (url.as_str(), self.anonymous_client.clone())
+            }
+            Some(url) => (url.as_str(), self.client.clone()),
+            None => (self.pick_server(session_id.as_deref()), self.client.clone()),
+        };
+        let url = format!("{server}/v1/chat/completions");
         let (tx, rx) = mpsc::channel::<TokenEvent>(32);
+        let model = self.model_name.clone();
+        let strict = self.strict_parsing;
+        let request_id = request_id.unwrap_or_else(|| "-".to_string());
+        self.mirror(&body, &request_id);
 
-        tokio::spawn(async move {
-            let resp = match client.post(url).json(&body).send().await {
-                Ok(r) => r,
-                Err(err) => {
-                    let _ = tx
-                        .send(TokenEvent {
-                            token: format!("error: request failed: {err}"),
-                            finished: true,
-                        })
-                        .await;
-                    return;
-                }
-            };
+        // Connect before handing back a stream so failures reach the caller
+        // as errors rather than as generated text.
+        let resp = match client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(resp) => resp,
+            Err(err) => {
+                tracing::error!(
+                    model = %model,
+                    request_id = %request_id,
+                    error = %err,
+                    "backend request failed"
+                );
+                return Err(ModelError::Backend(format!("request failed: {err}")));
+            }
+        };
 
+        tokio::spawn(async move {
             let mut stream = resp.bytes_stream();
             let mut buf = String::new();
+            let mut reconnects_left = reconnects;
+            // Bytes of generated text and tool-call fragments forwarded, and
+            // how much of a reconnected stream's replay is still to drop.
+            // Until the replay passes what was sent, none of its events
+            // (progress, logprobs, prompt_logprobs) are forwarded.
+            let mut sent = 0usize;
+            let mut sent_calls = 0usize;
+            let mut skip = 0usize;
+            let mut skip_calls = 0usize;
+            let mut replaying = false;
+            // Set while the latest connection's failure is already logged,
+            // so it isn't reported a second time as ending early.
+            let mut interrupted = false;
 
-            while let Some(chunk) = stream.next().await {
-                let Ok(bytes) = chunk else { break };
-                buf.push_str(&String::from_utf8_lossy(&bytes));
+            loop {
+                while let Some(chunk) = stream.next().await {
+                    let bytes = match chunk {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            tracing::error!(
+                                model = %model,
+                                request_id = %request_id,
+                                error = %err,
+                                "backend stream interrupted"
+                            );
+                            interrupted = true;
+                            break;
+                        }
+                    };
+                    buf.push_str(&String::from_utf8_lossy(&bytes));
 
-                loop {
-                    if let Some(idx) = buf.find("\n\n") {
+                    while let Some(idx) = buf.find("\n\n") {
                         let mut part = buf[..idx].trim().to_string();
                         buf.drain(..idx + 2);
 
@@ -334,12 +1996,72 @@ impl ModelBackend for LlamaServerBackend {
                                 .send(TokenEvent {
                                     token: String::new(),
                                     finished: true,
+                                    progress: None,
+                                    logprob: None,
+                                    tool_calls: None,
+                                    finish_reason: None,
+                                    prompt_logprobs: None,
+                                    malformed: false,
                                 })
                                 .await;
+                            drop(tx);
+                            drain(stream).await;
                             return;
                         }
 
-                        if let Ok(v) = serde_json::from_str::<Value>(&part) {
+                        let parsed = serde_json::from_str::<Value>(&part);
+                        if strict {
+                            let checked = match &parsed {
+                                Ok(v) => validate_chunk(v),
+                                Err(err) => Err(format!("invalid JSON: {err}")),
+                            };
+                            if let Err(error) = checked {
+                                // Only the size: the event may carry
+                                // generated text.
+                                tracing::warn!(
+                                    model = %model,
+                                    request_id = %request_id,
+                                    error = %error,
+                                    chunk_bytes = part.len(),
+                                    "backend sent a malformed event"
+                                );
+                                let _ = tx
+                                    .send(TokenEvent {
+                                        token: String::new(),
+                                        finished: true,
+                                        progress: None,
+                                        logprob: None,
+                                        tool_calls: None,
+                                        finish_reason: None,
+                                        prompt_logprobs: None,
+                                        malformed: true,
+                                    })
+                                    .await;
+                                return;
+                            }
+                        }
+
+                        if let Ok(v) = parsed {
+                            if let Some(progress) = v.get("prompt_progress") {
+                                let processed = progress.get("processed").and_then(Value::as_f64);
+                                let total = progress.get("total").and_then(Value::as_f64);
+                                if let (Some(processed), Some(total)) = (processed, total) {
+                                    if total > 0.0 && !replaying {
+                                        let _ = tx
+                                            .send(TokenEvent {
+                                                token: String::new(),
+                                                finished: false,
+                                                progress: Some((processed / total) as f32),
+                                                logprob: None,
+                                                tool_calls: None,
+                                                finish_reason: None,
+                                                prompt_logprobs: None,
+                                                malformed: false,
+                                            })
+                                            .await;
+                                    }
+                                }
+                            }
                             let token_text = v
                                 .get("token")
                                 .and_then(|t| t.get("text"))
@@ -350,26 +2072,25 @@ impl ModelBackend for LlamaServerBackend {
                                         .and_then(|t| t.as_str())
                                 })
                                 .or_else(|| {
-                                    v.get("choices")
-                                        .and_then(|c| c.get(0))
-                                        .and_then(|c0| {
-                                            c0.get("delta")
-                                                .and_then(|d| d.get("content"))
-                                                .and_then(|d| d.as_str())
-                                                .or_else(|| {
-                                                    c0.get("text").and_then(|d| d.as_str())
-                                                })
-                                        })
+                                    v.get("choices").and_then(|c| c.get(0)).and_then(|c0| {
+                                        c0.get("delta")
+                                            .and_then(|d| d.get("content"))
+                                            .and_then(|d| d.as_str())
+                                            .or_else(|| c0.get("text").and_then(|d| d.as_str()))
+                                    })
                                 })
                                 .unwrap_or_default()
                                 .to_string();
+                            let token_text = skip_replayed_text(&mut skip, token_text);
+                            sent += token_text.len();
 
                             let finish_reason = v
                                 .get("choices")
                                 .and_then(|c| c.get(0))
                                 .and_then(|c0| c0.get("finish_reason"))
                                 .and_then(|f| f.as_str())
-                                .unwrap_or("");
+                                .filter(|f| !f.is_empty())
+                                .map(str::to_string);
 
                             let done_flag = v
                                 .get("done")
@@ -377,38 +2098,158 @@ impl ModelBackend for LlamaServerBackend {
                                 .or_else(|| v.get("completed"))
                                 .and_then(|d| d.as_bool())
                                 .unwrap_or(false)
-                                || finish_reason == "stop";
+                                || finish_reason.is_some();
+
+                            let logprob = v
+                                .get("choices")
+                                .and_then(|c| c.get(0))
+                                .and_then(|c0| c0.get("logprobs"))
+                                .and_then(|l| l.get("content"))
+                                .and_then(|c| c.as_array())
+                                .map(|entries| {
+                                    entries
+                                        .iter()
+                                        .filter_map(|e| e.get("logprob").and_then(Value::as_f64))
+                                        .sum::<f64>() as f32
+                                });
+
+                            let tool_calls = v
+                                .get("choices")
+                                .and_then(|c| c.get(0))
+                                .and_then(|c0| c0.get("delta"))
+                                .and_then(|d| d.get("tool_calls"))
+                                .and_then(|t| {
+                                    serde_json::from_value::<Vec<ToolCallDelta>>(t.clone()).ok()
+                                })
+                                .filter(|calls| !calls.is_empty());
+                            let tool_calls = skip_replayed_calls(&mut skip_calls, tool_calls);
+                            sent_calls += tool_calls.as_ref().map_or(0, Vec::len);
+
+                            // vLLM puts these at the top level of a chat
+                            // response, and on the choice of a completion.
+                            let prompt_logprobs = v
+                                .get("prompt_logprobs")
+                                .or_else(|| {
+                                    v.get("choices")
+                                        .and_then(|c| c.get(0))
+                                        .and_then(|c0| c0.get("prompt_logprobs"))
+                                })
+                                .filter(|p| !p.is_null())
+                                .cloned();
+
+                            if replaying
+                                && (!token_text.is_empty() || tool_calls.is_some() || done_flag)
+                            {
+                                replaying = false;
+                            } else if replaying {
+                                continue;
+                            }
 
-                            if !token_text.is_empty() || done_flag {
+                            if !token_text.is_empty()
+                                || tool_calls.is_some()
+                                || prompt_logprobs.is_some()
+                                || done_flag
+                            {
                                 let _ = tx
                                     .send(TokenEvent {
                                         token: token_text,
                                         finished: done_flag,
+                                        progress: None,
+                                        logprob,
+                                        tool_calls,
+                                        finish_reason,
+                                        prompt_logprobs,
+                                        malformed: false,
                                     })
                                     .await;
                             }
                             if done_flag {
+                                drop(tx);
+                                drain(stream).await;
                                 return;
                             }
                         } else {
+                            tracing::warn!(
+                                model = %model,
+                                request_id = %request_id,
+                                error = "unparsable chunk",
+                                chunk_bytes = part.len(),
+                                "backend sent a non-JSON event"
+                            );
                             // Fallback: emit raw line content if JSON parse fails
                             let _ = tx
                                 .send(TokenEvent {
                                     token: part.clone(),
                                     finished: false,
+                                    progress: None,
+                                    logprob: None,
+                                    tool_calls: None,
+                                    finish_reason: None,
+                                    prompt_logprobs: None,
+                                    malformed: false,
                                 })
                                 .await;
                         }
-                    } else {
+                    }
+                }
+                // A seeded request replays the same text, so the part already
+                // forwarded can be skipped.
+                if reconnects_left == 0 {
+                    break;
+                }
+                reconnects_left -= 1;
+                tracing::warn!(
+                    model = %model,
+                    request_id = %request_id,
+                    forwarded_bytes = sent,
+                    "backend stream dropped, reconnecting"
+                );
+                match client
+                    .post(&url)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                {
+                    Ok(resp) => {
+                        stream = resp.bytes_stream();
+                        buf.clear();
+                        skip = sent;
+                        skip_calls = sent_calls;
+                        replaying = true;
+                        interrupted = false;
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            model = %model,
+                            request_id = %request_id,
+                            error = %err,
+                            "backend reconnect failed"
+                        );
+                        interrupted = true;
                         break;
                     }
                 }
             }
 
+            if !interrupted {
+                tracing::error!(
+                    model = %model,
+                    request_id = %request_id,
+                    error = "stream ended without a finish marker",
+                    "backend stream ended early"
+                );
+            }
             let _ = tx
                 .send(TokenEvent {
                     token: String::new(),
                     finished: true,
+                    progress: None,
+                    logprob: None,
+                    tool_calls: None,
+                    finish_reason: None,
+                    prompt_logprobs: None,
+                    malformed: false,
                 })
                 .await;
         });
@@ -416,3 +2257,921 @@ impl ModelBackend for LlamaServerBackend {
         Ok(Box::pin(ReceiverStream::new(rx)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Secret;
+    use crate::testing::{
+        capture_logs, model_config, numbered, params, test_config, MockBackend, MockKind, Script,
+        TestApp, MODEL,
+    };
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn manager(limits: LimitConfig) -> ModelManager {
+        ModelManager::new(limits, Arc::new(Metrics::default()))
+    }
+
+    fn slow_mock_script() -> Script {
+        Script {
+            reply: Some(numbered(100)),
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn status_reports_permits_held_by_running_generations() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let manager = manager(LimitConfig::default());
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let stream = manager.stream(MODEL, params("hi")).await.unwrap();
+        let status = &manager.status()[0];
+        assert_eq!((status.max_concurrent, status.available_permits), (2, 1));
+        drop(stream);
+        assert_eq!(manager.status()[0].available_permits, 2);
+
+        let app = TestApp::start(test_config(&mock)).await;
+        let _held = app.state.models.stream(MODEL, params("hi")).await.unwrap();
+        let status: Value = app.get("/admin/models/status").await.json().await.unwrap();
+        assert_eq!(status["data"][0]["max_concurrent"], 2);
+        assert_eq!(status["data"][0]["available_permits"], 1);
+        assert_eq!(status["data"][0]["queued"], 0);
+    }
+
+    #[tokio::test]
+    async fn suggest_model_names_the_closest_loaded_model() {
+        let mock = MockBackend::start().await;
+        let manager = manager(LimitConfig::default()).with_suggestion_distance(2);
+        for name in ["llama-7b", "mistral-7b"] {
+            manager
+                .load_model(model_config(name, &mock), false)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            manager.suggest_model("llama-7c").as_deref(),
+            Some("llama-7b")
+        );
+        assert_eq!(
+            manager.suggest_model("mistrl-7b").as_deref(),
+            Some("mistral-7b")
+        );
+        assert_eq!(manager.suggest_model("gpt-4"), None);
+
+        let mut cfg = test_config(&mock);
+        cfg.model_suggestion_distance = 2;
+        cfg.models[0].name = "llama-7b".to_string();
+        let app = TestApp::start(cfg).await;
+        let resp = app
+            .post(
+                "/v1/chat/completions",
+                json!({"model": "llama-7c", "messages": [{"role": "user", "content": "hi"}]}),
+            )
+            .await;
+        assert_eq!(resp.status(), 404);
+        let body: Value = resp.json().await.unwrap();
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .contains("did you mean 'llama-7b'"),
+            "{body}"
+        );
+    }
+
+    async fn overflow_manager(mock: &MockBackend, overflow: OverflowBehavior) -> ModelManager {
+        let manager = manager(LimitConfig {
+            max_concurrent: 1,
+            queue_depth: 1,
+            ..Default::default()
+        });
+        let cfg = ModelConfig {
+            overflow_behavior: overflow,
+            ..model_config(MODEL, mock)
+        };
+        manager.load_model(cfg, false).await.unwrap();
+        manager
+    }
+
+    #[tokio::test]
+    async fn overflow_behavior_rejects_queues_or_sheds_the_oldest() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+
+        let manager = overflow_manager(&mock, OverflowBehavior::Reject).await;
+        let held = manager.stream(MODEL, params("hi")).await.unwrap();
+        let rejected = manager.stream(MODEL, params("hi")).await;
+        assert!(matches!(rejected, Err(ModelError::Overloaded)));
+        drop(held);
+
+        let manager = Arc::new(overflow_manager(&mock, OverflowBehavior::Queue).await);
+        let held = manager.stream(MODEL, params("hi")).await.unwrap();
+        let waiter = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.stream(MODEL, params("hi")).await.map(drop) }
+        });
+        while manager.status()[0].queued == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // The one waiter slot is taken.
+        let rejected = manager.stream(MODEL, params("hi")).await;
+        assert!(matches!(rejected, Err(ModelError::Overloaded)));
+        assert!(!waiter.is_finished());
+        drop(held);
+        assert!(waiter.await.unwrap().is_ok());
+
+        let manager = overflow_manager(&mock, OverflowBehavior::ShedOldest).await;
+        let oldest = manager.stream(MODEL, params("hi")).await.unwrap();
+        let consumer = tokio::spawn(oldest.collect::<Vec<TokenEvent>>());
+        let newest = manager.stream(MODEL, params("hi")).await.unwrap();
+        let shed = consumer.await.unwrap();
+        let last = shed.last().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.finish_reason.as_deref(), Some("cancelled"));
+        let events: Vec<TokenEvent> = newest.collect().await;
+        assert_eq!(
+            events.last().unwrap().finish_reason.as_deref(),
+            Some("stop")
+        );
+    }
+
+    #[tokio::test]
+    async fn evict_idle_unloads_only_unpinned_idle_models() {
+        let mock = MockBackend::replying(&["ok"]).await;
+        let manager = manager(LimitConfig::default());
+        for name in ["stale", "fresh", "busy", "pinned"] {
+            manager
+                .load_model(model_config(name, &mock), false)
+                .await
+                .unwrap();
+        }
+        manager.pin("pinned");
+        let ttl = Duration::from_millis(100);
+        let _running = manager.stream("busy", params("hi")).await.unwrap();
+        tokio::time::sleep(ttl * 2).await;
+        let used: Vec<TokenEvent> = manager
+            .stream("fresh", params("hi"))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert!(used.last().unwrap().finished);
+
+        assert_eq!(manager.evict_idle(ttl).await, ["stale"]);
+        assert!(manager.model_status("stale").is_none());
+        for name in ["fresh", "busy", "pinned"] {
+            assert!(manager.model_status(name).is_some(), "{name} evicted");
+        }
+    }
+
+    #[tokio::test]
+    async fn status_shows_the_last_error_until_a_generation_succeeds() {
+        let mock = MockBackend::with_script(Script {
+            fail_status: Some(500),
+            ..Default::default()
+        })
+        .await;
+        let app = TestApp::start(test_config(&mock)).await;
+        let last_error = || async {
+            let status: Value = app.get("/admin/models/status").await.json().await.unwrap();
+            status["data"][0]["last_error"].clone()
+        };
+        assert!(last_error().await.is_null());
+
+        let failed = app.chat("hi", json!({"stream": false})).await;
+        assert!(failed.status().is_server_error());
+        let error = last_error().await;
+        assert!(
+            error["message"].as_str().unwrap().contains("500"),
+            "{error}"
+        );
+        assert!(error["at"].as_u64().unwrap() > 0);
+
+        mock.script(|s| s.fail_status = None);
+        assert_eq!(app.chat("hi", json!({"stream": false})).await.status(), 200);
+        assert!(last_error().await.is_null());
+    }
+
+    #[tokio::test]
+    async fn global_cap_limits_concurrency_across_models() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let manager = manager(LimitConfig {
+            max_concurrent: 2,
+            global_max_concurrent: Some(3),
+            ..Default::default()
+        });
+        for name in ["a", "b"] {
+            manager
+                .load_model(model_config(name, &mock), false)
+                .await
+                .unwrap();
+        }
+        let mut running = Vec::new();
+        for name in ["a", "a", "b"] {
+            running.push(manager.stream(name, params("hi")).await.unwrap());
+        }
+        assert_eq!(manager.global_available(), Some(0));
+        let rejected = manager.stream("b", params("hi")).await;
+        assert!(matches!(rejected, Err(ModelError::Overloaded)));
+        // The model permit taken before the global one was refused is back.
+        assert_eq!(manager.model_status("b").unwrap().available_permits, 1);
+
+        running.pop();
+        assert!(manager.stream("b", params("hi")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn raising_limits_after_shrinking_while_busy_keeps_the_new_size() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let manager = manager(LimitConfig {
+            max_concurrent: 4,
+            ..Default::default()
+        });
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let available = || manager.model_status(MODEL).unwrap().available_permits;
+        let settles_at = |permits: usize| {
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while available() != permits {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+        };
+        let mut running = Vec::new();
+        for _ in 0..4 {
+            running.push(manager.stream(MODEL, params("hi")).await.unwrap());
+        }
+        manager
+            .update_limits(|limits| limits.max_concurrent = 1)
+            .unwrap();
+        // Two finish and their permits are retired; one more is still owed.
+        running.truncate(2);
+        settles_at(0).await.unwrap();
+
+        // The raise cancels what is still owed instead of feeding the shrink.
+        manager
+            .update_limits(|limits| limits.max_concurrent = 3)
+            .unwrap();
+        settles_at(1).await.unwrap();
+        running.clear();
+        settles_at(3).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let status = manager.model_status(MODEL).unwrap();
+        assert_eq!(status.max_concurrent, 3);
+        assert_eq!(status.available_permits, 3);
+    }
+
+    #[tokio::test]
+    async fn models_without_a_backend_use_the_configured_default() {
+        let mock = MockBackend::start().await;
+        let manager = manager(LimitConfig::default())
+            .with_default_backend("llama-server".to_string())
+            .unwrap();
+        let summary = manager
+            .load_model(
+                ModelConfig {
+                    backend: None,
+                    ..model_config(MODEL, &mock)
+                },
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.backend, "llama-server");
+        assert_eq!(mock.requests()[0].path, "/health");
+
+        let unknown = ModelManager::new(LimitConfig::default(), Arc::new(Metrics::default()))
+            .with_default_backend("gpt".to_string());
+        assert!(matches!(unknown, Err(ModelError::InvalidConfig(_))));
+        let cfg = crate::config::AppConfig {
+            default_backend: "gpt".to_string(),
+            ..test_config(&mock)
+        };
+        let err = crate::app_state(&cfg).err().unwrap();
+        assert!(err.to_string().contains("default_backend 'gpt'"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn per_model_queue_depth_overrides_the_global_one() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let manager = Arc::new(manager(LimitConfig {
+            max_concurrent: 1,
+            queue_depth: 2,
+            ..Default::default()
+        }));
+        for (name, queue_depth) in [("short", Some(1)), ("long", Some(3)), ("default", None)] {
+            let cfg = ModelConfig {
+                overflow_behavior: OverflowBehavior::Queue,
+                queue_depth,
+                ..model_config(name, &mock)
+            };
+            manager.load_model(cfg, false).await.unwrap();
+        }
+        let mut held = Vec::new();
+        for (name, accepted) in [("short", 1), ("long", 3), ("default", 2)] {
+            held.push(manager.stream(name, params("hi")).await.unwrap());
+            for _ in 0..accepted {
+                let manager = manager.clone();
+                tokio::spawn(async move { manager.stream(name, params("hi")).await.map(drop) });
+            }
+            while manager.model_status(name).unwrap().queued < accepted {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            let rejected = manager.stream(name, params("hi")).await;
+            assert!(matches!(rejected, Err(ModelError::Overloaded)), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_fails_fast_while_open_then_probes() {
+        let mock = MockBackend::with_script(Script {
+            fail_status: Some(500),
+            ..Default::default()
+        })
+        .await;
+        let manager = manager(LimitConfig::default()).with_circuit_breaker(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_seconds: 1,
+        });
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let circuit = || manager.model_status(MODEL).unwrap().circuit;
+        for _ in 0..2 {
+            assert_eq!(circuit(), CircuitState::Closed);
+            assert!(manager.stream(MODEL, params("hi")).await.is_err());
+        }
+        assert_eq!(circuit(), CircuitState::Open);
+
+        let sent = mock.generations().len();
+        let started = Instant::now();
+        let err = manager.stream(MODEL, params("hi")).await.err().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(
+            err.to_string(),
+            ModelError::Backend("circuit open".to_string()).to_string()
+        );
+        assert_eq!(mock.generations().len(), sent);
+
+        mock.script(|s| s.fail_status = None);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(manager.stream(MODEL, params("hi")).await.is_ok());
+        assert_eq!(circuit(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn a_dropped_half_open_probe_reopens_the_circuit() {
+        let mock = MockBackend::with_script(Script {
+            fail_status: Some(500),
+            ..Default::default()
+        })
+        .await;
+        let manager = manager(LimitConfig::default()).with_circuit_breaker(CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown_seconds: 1,
+        });
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let circuit = || manager.model_status(MODEL).unwrap().circuit;
+        assert!(manager.stream(MODEL, params("hi")).await.is_err());
+        assert_eq!(circuit(), CircuitState::Open);
+
+        // The probe's backend hangs and its caller gives up mid-connect.
+        mock.script(|s| {
+            s.fail_status = None;
+            s.answer_delay = Duration::from_secs(30);
+        });
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let probe = manager.stream(MODEL, params("hi"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), probe)
+            .await
+            .is_err());
+        assert_eq!(circuit(), CircuitState::Open);
+
+        mock.script(|s| s.answer_delay = Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(manager.stream(MODEL, params("hi")).await.is_ok());
+        assert_eq!(circuit(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn backend_failures_are_logged_with_model_and_request_id() {
+        let (logs, _guard) = capture_logs(tracing::Level::ERROR);
+        let manager = manager(LimitConfig::default());
+        let refused = ModelConfig {
+            name: "down".to_string(),
+            backend: Some("llama-server".to_string()),
+            server_url: Some("http://127.0.0.1:1".to_string()),
+            ..Default::default()
+        };
+        manager.load_model(refused, false).await.unwrap();
+        let err = manager
+            .stream(
+                "down",
+                GenerateParams {
+                    request_id: Some("req-refused".to_string()),
+                    ..params("hi")
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, ModelError::Backend(_)));
+        let text = logs.text();
+        let line = text.lines().find(|l| l.contains("req-refused")).unwrap();
+        assert!(line.contains("ERROR"), "{line}");
+        assert!(line.contains("backend request failed"), "{line}");
+        assert!(line.contains("model=down"), "{line}");
+        assert!(line.contains("error="), "{line}");
+
+        let mock = MockBackend::with_script(Script {
+            reply: Some(numbered(5)),
+            drop_after: Some(2),
+            ..Default::default()
+        })
+        .await;
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let params = GenerateParams {
+            request_id: Some("req-dropped".to_string()),
+            ..params("hi")
+        };
+        let _: Vec<TokenEvent> = manager.stream(MODEL, params).await.unwrap().collect().await;
+        let text = logs.text();
+        let line = text.lines().find(|l| l.contains("req-dropped")).unwrap();
+        assert!(line.contains("backend stream interrupted"), "{line}");
+        assert!(line.contains("model=m"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn backend_requests_carry_the_configured_api_key() {
+        let mock = MockBackend::replying(&["ok"]).await;
+        let manager = manager(LimitConfig::default());
+        let keyed = ModelConfig {
+            api_key: Some(Secret::new("sk-config".to_string())),
+            ..model_config("keyed", &mock)
+        };
+        assert!(!format!("{keyed:?}").contains("sk-config"));
+        manager.load_model(keyed, false).await.unwrap();
+        std::env::set_var("LLMIS_TEST_BACKEND_KEY", "sk-env");
+        let from_env = ModelConfig {
+            api_key_env: Some("LLMIS_TEST_BACKEND_KEY".to_string()),
+            ..model_config("from-env", &mock)
+        };
+        manager.load_model(from_env, false).await.unwrap();
+        manager
+            .load_model(model_config("open", &mock), false)
+            .await
+            .unwrap();
+        for name in ["keyed", "from-env", "open"] {
+            let _: Vec<TokenEvent> = manager
+                .stream(name, params("hi"))
+                .await
+                .unwrap()
+                .collect()
+                .await;
+        }
+
+        let auth: Vec<(String, Option<String>)> = mock
+            .requests()
+            .into_iter()
+            .map(|r| {
+                let auth = r
+                    .headers
+                    .get("authorization")
+                    .map(|v| v.to_str().unwrap().to_string());
+                (r.path, auth)
+            })
+            .collect();
+        let bearer = |key: &str| Some(format!("Bearer {key}"));
+        assert_eq!(
+            auth,
+            [
+                ("/health".to_string(), bearer("sk-config")),
+                ("/health".to_string(), bearer("sk-env")),
+                ("/health".to_string(), None),
+                ("/v1/chat/completions".to_string(), bearer("sk-config")),
+                ("/v1/chat/completions".to_string(), bearer("sk-env")),
+                ("/v1/chat/completions".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn capacity_reports_permits_taken_by_running_requests() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let mut cfg = test_config(&mock);
+        cfg.models[0].overflow_behavior = OverflowBehavior::Queue;
+        let app = TestApp::start(cfg).await;
+        let capacity = || async {
+            let body: Value = app.get("/admin/capacity").await.json().await.unwrap();
+            body["data"][0].clone()
+        };
+        let idle = capacity().await;
+        assert_eq!(idle["name"], MODEL);
+        assert_eq!(idle["available_permits"], 2);
+        assert_eq!(idle["queue_slots"], LimitConfig::default().queue_depth);
+        assert_eq!(idle["circuit"], "closed");
+        assert_eq!(idle["accepting"], true);
+
+        let _first = app.state.models.stream(MODEL, params("hi")).await.unwrap();
+        assert_eq!(capacity().await["available_permits"], 1);
+        let _second = app.state.models.stream(MODEL, params("hi")).await.unwrap();
+        let full = capacity().await;
+        assert_eq!(full["available_permits"], 0);
+        assert_eq!(full["accepting"], true);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_record_their_wait() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let metrics = Arc::new(Metrics::default().with_queue_wait_buckets(vec![0.05, 1.0]));
+        let manager = Arc::new(ModelManager::new(
+            LimitConfig {
+                max_concurrent: 1,
+                ..Default::default()
+            },
+            metrics.clone(),
+        ));
+        let cfg = ModelConfig {
+            overflow_behavior: OverflowBehavior::Queue,
+            ..model_config(MODEL, &mock)
+        };
+        manager.load_model(cfg, false).await.unwrap();
+        let held = manager.stream(MODEL, params("hi")).await.unwrap();
+        let waiter = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.stream(MODEL, params("hi")).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(held);
+        waiter.await.unwrap().unwrap();
+
+        let text = metrics.render_prometheus();
+        // The first request got a permit at once, the second waited.
+        assert!(
+            text.contains("llmis_queue_wait_seconds_bucket{le=\"0.05\"} 1\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("llmis_queue_wait_seconds_bucket{le=\"1\"} 2\n"),
+            "{text}"
+        );
+        let sum: f64 = text
+            .lines()
+            .find_map(|line| line.strip_prefix("llmis_queue_wait_seconds_sum "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(sum >= 0.15, "{sum}");
+    }
+
+    #[test]
+    fn device_strings_are_validated() {
+        for device in ["cpu", "cuda:0", "cuda:12", "metal", "vulkan"] {
+            assert!(validate_device(device).is_ok(), "{device} rejected");
+        }
+        for device in ["gpu", "cuda", "cuda:", "cuda:x", "cuda:-1", "CPU", ""] {
+            let err = validate_device(device).unwrap_err();
+            assert!(matches!(err, ModelError::InvalidConfig(_)), "{device}");
+        }
+    }
+
+    #[tokio::test]
+    async fn loading_on_an_unknown_device_fails_with_400() {
+        let (app, mock) = TestApp::with_mock().await;
+        let resp = app
+            .post("/admin/models/load", json!({"name": "gpu-model", "backend": "llama-server", "server_url": mock.url, "device": "gpu"})).await;
+        assert_eq!(resp.status(), 400);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(
+            body["error"],
+            "unknown device 'gpu', expected cpu, cuda:N, metal or vulkan"
+        );
+        assert!(app.state.models.model_status("gpu-model").is_none());
+    }
+
+    #[tokio::test]
+    async fn cold_start_is_recorded_for_the_first_request_only() {
+        let mock = MockBackend::replying(&["a", "b"]).await;
+        let metrics = Arc::new(Metrics::default());
+        let manager = ModelManager::new(LimitConfig::default(), metrics.clone());
+        let cold_start = || {
+            let text = metrics.render_prometheus();
+            text.lines()
+                .find_map(|line| line.strip_prefix("llmis_cold_start_seconds{model=\"m\"} "))
+                .map(|secs| secs.parse::<f64>().unwrap())
+        };
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        assert_eq!(cold_start(), None);
+
+        let stream = manager.stream(MODEL, params("hi")).await.unwrap();
+        stream.collect::<Vec<TokenEvent>>().await;
+        let first = cold_start().expect("cold start recorded");
+        assert!(first < 0.1, "{first}");
+
+        // A slower second request leaves the recorded value alone.
+        mock.script(|s| s.delay = Duration::from_millis(100));
+        let stream = manager.stream(MODEL, params("hi")).await.unwrap();
+        stream.collect::<Vec<TokenEvent>>().await;
+        assert_eq!(cold_start(), Some(first));
+
+        manager.unload_model(MODEL).await.unwrap();
+        assert_eq!(cold_start(), None);
+        manager
+            .load_model(model_config(MODEL, &mock), false)
+            .await
+            .unwrap();
+        let stream = manager.stream(MODEL, params("hi")).await.unwrap();
+        stream.collect::<Vec<TokenEvent>>().await;
+        assert!(cold_start().unwrap() >= 0.1);
+    }
+
+    #[tokio::test]
+    async fn shadowed_requests_reach_the_shadow_but_answer_from_the_primary() {
+        let primary = MockBackend::replying(&["primary"]).await;
+        let shadow = MockBackend::with_script(Script {
+            reply: Some(vec!["shadow".to_string()]),
+            delay: Duration::from_secs(1),
+            ..Default::default()
+        })
+        .await;
+        let mut cfg = test_config(&primary);
+        cfg.models[0].shadow_url = Some(shadow.url.clone());
+        cfg.models[0].shadow_percent = 100.0;
+        let app = TestApp::start(cfg).await;
+
+        let started = Instant::now();
+        let body: Value = app
+            .chat("hi there", json!({"stream": false}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "primary");
+        // The slow shadow does not hold up the client.
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while shadow.generations().is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mirrored = shadow.generations();
+        assert_eq!(mirrored.len(), 1);
+        let primary = primary.generations();
+        assert_eq!(primary.len(), 1);
+        assert_eq!(mirrored[0]["messages"], primary[0]["messages"]);
+    }
+
+    #[tokio::test]
+    async fn unsampled_requests_are_not_shadowed() {
+        let primary = MockBackend::start().await;
+        let shadow = MockBackend::start().await;
+        let mut cfg = test_config(&primary);
+        cfg.models[0].shadow_url = Some(shadow.url.clone());
+        let app = TestApp::start(cfg).await;
+        for _ in 0..5 {
+            app.chat("hi", json!({"stream": false})).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(shadow.generations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn loading_a_loaded_name_conflicts_unless_forced() {
+        let (app, old) = TestApp::with_mock().await;
+        let new = MockBackend::replying(&["new"]).await;
+        let load = |force: Option<bool>| {
+            let mut body = json!({"name": MODEL, "backend": "llama-server", "server_url": new.url});
+            if let Some(force) = force {
+                body["force"] = json!(force);
+            }
+            app.post("/admin/models/load", body)
+        };
+
+        let resp = load(None).await;
+        assert_eq!(resp.status(), 409);
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(body["error"], "model already loaded: m");
+        assert_eq!(load(Some(false)).await.status(), 409);
+        app.chat("hi", json!({"stream": false})).await;
+        assert_eq!((old.generations().len(), new.generations().len()), (1, 0));
+
+        // A generation already running on the old handle finishes there.
+        old.script(|s| *s = slow_mock_script());
+        let mut running = app.state.models.stream(MODEL, params("hi")).await.unwrap();
+        assert_eq!(load(Some(true)).await.status(), 201);
+        let mut tokens = 0;
+        while let Some(event) = running.next().await {
+            tokens += usize::from(!event.token.is_empty());
+        }
+        assert_eq!(tokens, 100);
+
+        let body: Value = app
+            .chat("hi", json!({"stream": false}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "new");
+        assert_eq!((old.generations().len(), new.generations().len()), (2, 1));
+        assert_eq!(app.state.models.list_models().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn device_cap_limits_combined_concurrency_of_models_on_it() {
+        let mock = MockBackend::with_script(slow_mock_script()).await;
+        let manager = manager(LimitConfig {
+            max_concurrent: 2,
+            device_max_concurrent: [("cuda:0".to_string(), 2)].into(),
+            ..Default::default()
+        });
+        for (name, device) in [("a", "cuda:0"), ("b", "cuda:0"), ("c", "cuda:1")] {
+            let cfg = ModelConfig {
+                device: Some(device.to_string()),
+                ..model_config(name, &mock)
+            };
+            manager.load_model(cfg, false).await.unwrap();
+        }
+        let mut running = Vec::new();
+        for name in ["a", "b"] {
+            running.push(manager.stream(name, params("hi")).await.unwrap());
+        }
+        // Each model has a free permit of its own, but the GPU is full.
+        for name in ["a", "b"] {
+            let rejected = manager.stream(name, params("hi")).await;
+            assert!(matches!(rejected, Err(ModelError::Overloaded)));
+        }
+        // Other devices are not affected.
+        let _c = manager.stream("c", params("hi")).await.unwrap();
+        let _c2 = manager.stream("c", params("hi")).await.unwrap();
+
+        running.pop();
+        assert!(manager.stream("a", params("hi")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn auto_backend_detects_each_server_api() {
+        let manager = manager(LimitConfig::default());
+        for (kind, expected) in [
+            (MockKind::LlamaServer, "llama-server"),
+            (MockKind::Ollama, "ollama"),
+            (MockKind::Tgi, "tgi"),
+            (MockKind::Vllm, "vllm"),
+        ] {
+            let mock = MockBackend::with_script(Script {
+                kind,
+                ..Default::default()
+            })
+            .await;
+            let cfg = ModelConfig {
+                backend: Some("auto".to_string()),
+                ..model_config(expected, &mock)
+            };
+            let summary = manager.load_model(cfg, false).await.unwrap();
+            assert_eq!(summary.backend, expected);
+            let stream = manager.stream(expected, params("hi there")).await.unwrap();
+            let text: String = stream.map(|event| event.token).collect().await;
+            assert_eq!(text, "hi there");
+        }
+
+        let unreachable = ModelConfig {
+            backend: Some("auto".to_string()),
+            server_url: Some("http://127.0.0.1:1".to_string()),
+            ..Default::default()
+        };
+        let err = manager
+            .load_model(
+                ModelConfig {
+                    name: "gone".to_string(),
+                    ..unreachable
+                },
+                false,
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unreachable"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn pooled_backend_connections_are_reused_unless_disabled() {
+        for (max_idle, reused) in [(None, true), (Some(0), false)] {
+            let mock = MockBackend::replying(&["ok"]).await;
+            let manager =
+                manager(LimitConfig::default()).with_pool(Duration::from_secs(90), max_idle);
+            manager
+                .load_model(model_config(MODEL, &mock), false)
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                let stream = manager.stream(MODEL, params("hi")).await.unwrap();
+                stream.collect::<Vec<_>>().await;
+                // The connection goes back to the pool once the backend
+                // task has read the rest of the body.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            let requests = mock.requests().len();
+            let expected = if reused { 1 } else { requests };
+            assert_eq!(mock.connections(), expected, "max_idle {max_idle:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_loads_are_limited_to_the_configured_slots() {
+        for (max_loads, serialized) in [(Some(1), true), (None, false)] {
+            let mock = MockBackend::with_script(Script {
+                health_delay: Duration::from_millis(500),
+                ..Default::default()
+            })
+            .await;
+            let manager = manager(LimitConfig::default()).with_max_concurrent_loads(max_loads);
+            let loads =
+                ["a", "b", "c"].map(|name| manager.load_model(model_config(name, &mock), false));
+            for summary in futures::future::join_all(loads).await {
+                summary.unwrap();
+            }
+            assert_eq!(manager.list_models().len(), 3);
+            // Each load probes the backend's health once.
+            assert_eq!(mock.requests().len(), 3);
+            if serialized {
+                assert_eq!(mock.peak_concurrency(), 1);
+            } else {
+                assert!(mock.peak_concurrency() > 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn loads_probe_the_configured_health_path_on_every_replica() {
+        let (first, second) = (MockBackend::start().await, MockBackend::start().await);
+        let manager = manager(LimitConfig::default());
+        let cfg = ModelConfig {
+            health_path: Some("/v1/models".to_string()),
+            replicas: [&first, &second]
+                .map(|mock| crate::config::ReplicaConfig {
+                    url: mock.url.clone(),
+                    weight: 1,
+                })
+                .to_vec(),
+            ..model_config(MODEL, &first)
+        };
+        manager.load_model(cfg.clone(), false).await.unwrap();
+        for mock in [&first, &second] {
+            let paths: Vec<String> = mock.requests().into_iter().map(|r| r.path).collect();
+            assert_eq!(paths, ["/v1/models"]);
+        }
+
+        let auto = ModelConfig {
+            name: "auto".to_string(),
+            backend: Some("auto".to_string()),
+            health_path: Some("/healthz".to_string()),
+            ..model_config(MODEL, &second)
+        };
+        manager.load_model(auto, false).await.unwrap();
+        assert_eq!(second.requests()[1].path, "/healthz");
+        assert!(second.requests().iter().all(|r| r.path != "/health"));
+
+        let relative = ModelConfig {
+            name: "relative".to_string(),
+            health_path: Some("ready".to_string()),
+            ..cfg
+        };
+        let err = manager.load_model(relative, false).await.err().unwrap();
+        assert!(
+            err.to_string().contains("health_path must start with '/'"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn backend_token_counts_come_from_the_first_replica() {
+        let replica = MockBackend::start().await;
+        let manager = manager(LimitConfig::default()).with_token_counter(TokenCounterKind::Backend);
+        let cfg = ModelConfig {
+            server_url: None,
+            replicas: vec![crate::config::ReplicaConfig {
+                url: replica.url.clone(),
+                weight: 1,
+            }],
+            ..model_config(MODEL, &replica)
+        };
+        manager.load_model(cfg, false).await.unwrap();
+        assert_eq!(manager.count_tokens(MODEL, "one two three").await, 3);
+        assert!(replica.requests().iter().any(|r| r.path == "/tokenize"));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::breaker::CircuitState;
+use crate::config::{LimitConfig, OverflowBehavior, ReplicaConfig};
+use crate::model::{
+    FunctionCallDelta, LastError, LoadStage, ModelCapabilities, ModelCapacity, ModelStatus,
+    ModelSummary, ToolCallDelta,
+};
+use crate::routes::{
+    ApiErrorResponse, CacheFlushResponse, CapacityResponse, ChatChoice, ChatCompletionRequest,
+    ChatCompletionResponse, ChatMessage, CompletionRequest, HealthResponse, LimitsUpdate,
+    LoadModelRequest, MaintenanceMode, ModelListResponse, ModelStatusResponse,
+    ModerationCategories, ModerationInput, ModerationRequest, ModerationResponse, ModerationResult,
+    TokenLogprob, UnloadModelRequest, Usage, VersionResponse,
+};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "LLMIS",
+        description = "OpenAI-compatible LLM inference service"
+    ),
+    paths(
+        crate::routes::healthz,
+        crate::routes::version,
+        crate::routes::metrics_handler,
+        crate::routes::chat_completions,
+        crate::routes::completions,
+        crate::routes::moderations,
+        crate::routes::list_models,
+        crate::routes::load_model,
+        crate::routes::load_model_stream,
+        crate::routes::unload_model,
+        crate::routes::model_status,
+        crate::routes::capacity,
+        crate::routes::update_limits,
+        crate::routes::set_maintenance,
+        crate::routes::flush_cache,
+    ),
+    components(schemas(
+        HealthResponse,
+        VersionResponse,
+        ChatMessage,
+        ToolCallDelta,
+        FunctionCallDelta,
+        ChatCompletionRequest,
+        CompletionRequest,
+        ChatChoice,
+        TokenLogprob,
+        Usage,
+        ChatCompletionResponse,
+        ModelSummary,
+        ModelCapabilities,
+        LoadStage,
+        ModelListResponse,
+        ModelStatus,
+        LastError,
+        CircuitState,
+        ModelStatusResponse,
+        ModelCapacity,
+        CapacityResponse,
+        LimitConfig,
+        LimitsUpdate,
+        MaintenanceMode,
+        CacheFlushResponse,
+        LoadModelRequest,
+        OverflowBehavior,
+        ReplicaConfig,
+        UnloadModelRequest,
+        ModerationRequest,
+        ModerationInput,
+        ModerationResponse,
+        ModerationResult,
+        ModerationCategories,
+        ApiErrorResponse,
+    ))
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::TestApp;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn openapi_json_documents_inference_admin_and_health_paths() {
+        let (app, _mock) = TestApp::with_mock().await;
+        let spec: Value = app.get("/openapi.json").await.json().await.unwrap();
+        for path in [
+            "/v1/chat/completions",
+            "/v1/completions",
+            "/v1/models",
+            "/admin/models/load",
+            "/admin/limits",
+            "/healthz",
+            "/metrics",
+        ] {
+            assert!(spec["paths"][path].is_object(), "{path} is not documented");
+        }
+        let schemas = &spec["components"]["schemas"];
+        assert!(schemas["ChatCompletionRequest"]["properties"]["messages"].is_object());
+        assert!(schemas["ApiErrorResponse"].is_object());
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Allows `rpm` requests per minute, in bursts of up to `rpm`.
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    inner: Mutex<BucketInner>,
+}
+
+struct BucketInner {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn per_minute(rpm: u32) -> Self {
+        let capacity = rpm.max(1) as f64;
+        Self {
+            capacity,
+            per_second: capacity / 60.0,
+            inner: Mutex::new(BucketInner {
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token, or says how long until one is available.
+    pub fn try_take(&self) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(inner.refilled_at).as_secs_f64();
+        inner.tokens = (inner.tokens + elapsed * self.per_second).min(self.capacity);
+        inner.refilled_at = now;
+        if inner.tokens >= 1.0 {
+            inner.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - inner.tokens) / self.per_second,
+            ))
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+/// Spots a generation stuck in a loop: trips once a run of `n` consecutive
+/// tokens has repeated more than `max_repeats` times in a row, the text
+/// cycling every `n` tokens or fewer. The same phrase coming up again after
+/// other text is not a loop.
+pub struct RepetitionDetector {
+    n: usize,
+    max_repeats: usize,
+    /// The last `n` tokens.
+    window: VecDeque<String>,
+    /// For each period `p` in `1..=n` (at `p - 1`), how many of the latest
+    /// tokens in a row equal the token `p` before them.
+    runs: Vec<usize>,
+}
+
+impl RepetitionDetector {
+    /// `None` when detection is off (`n` or `max_repeats` of 0).
+    pub fn new(n: usize, max_repeats: usize) -> Option<Self> {
+        (n > 0 && max_repeats > 0).then(|| Self {
+            n,
+            max_repeats,
+            window: VecDeque::with_capacity(n),
+            runs: vec![0; n],
+        })
+    }
+
+    /// Feeds one token and returns whether generation should end.
+    pub fn push(&mut self, token: &str) -> bool {
+        if token.is_empty() {
+            return false;
+        }
+        let len = self.window.len();
+        for (i, run) in self.runs.iter_mut().enumerate() {
+            let period = i + 1;
+            if period <= len && self.window[len - period] == token {
+                *run += 1;
+            } else {
+                *run = 0;
+            }
+        }
+        self.window.push_back(token.to_string());
+        if self.window.len() > self.n {
+            self.window.pop_front();
+        }
+        // Text repeating every `period` tokens for `run + period` tokens
+        // holds `(run + period - n) / period + 1` back-to-back copies of its
+        // last n-gram; the first is not a repeat.
+        self.runs
+            .iter()
+            .enumerate()
+            .any(|(i, &run)| run >= self.n + self.max_repeats * (i + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        chunks, sse, streamed_finish_reason, streamed_text, test_config, MockBackend, Script,
+        TestApp,
+    };
+    use serde_json::{json, Value};
+
+    fn looping(times: usize) -> Vec<String> {
+        [" round", " and", " round"]
+            .repeat(times)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn trips_on_the_repeat_past_the_limit() {
+        assert!(RepetitionDetector::new(0, 2).is_none());
+        assert!(RepetitionDetector::new(3, 0).is_none());
+        let mut detector = RepetitionDetector::new(3, 2).unwrap();
+        let tripped = looping(10).iter().position(|token| detector.push(token));
+        // The fourth occurrence of the first trigram is the third repeat.
+        assert_eq!(tripped, Some(11));
+
+        let mut detector = RepetitionDetector::new(2, 1).unwrap();
+        let varied = ["the", "cat", "sat", "on", "the", "mat", "the", "cat"];
+        assert!(!varied.iter().any(|token| detector.push(token)));
+
+        let mut detector = RepetitionDetector::new(3, 2).unwrap();
+        let stutter = ["a", "b"].repeat(5);
+        // Copies of the last trigram overlap when the cycle is shorter.
+        let tripped = stutter.iter().position(|token| detector.push(token));
+        assert_eq!(tripped, Some(8));
+    }
+
+    #[test]
+    fn long_text_reusing_phrases_does_not_trip() {
+        let mut detector = RepetitionDetector::new(3, 2).unwrap();
+        // Every trigram recurs hundreds of times, but never back to back.
+        let text: Vec<String> = (0..500)
+            .flat_map(|i| {
+                ["the", "value", "of", "item"]
+                    .map(String::from)
+                    .into_iter()
+                    .chain([i.to_string(), "is".to_string(), "set".to_string()])
+            })
+            .collect();
+        assert!(!text.iter().any(|token| detector.push(token)));
+    }
+
+    #[tokio::test]
+    async fn looping_generations_end_early_with_repetition() {
+        let mock = MockBackend::with_script(Script {
+            reply: Some(looping(50)),
+            ..Default::default()
+        })
+        .await;
+        let mut cfg = test_config(&mock);
+        cfg.repetition_stop.ngram_size = 3;
+        cfg.repetition_stop.max_repeats = 2;
+        let app = TestApp::start(cfg).await;
+        let expected = looping(4).concat();
+
+        let events = sse(app.chat("hi", json!({"stream": true})).await).await;
+        let chunks = chunks(&events);
+        assert_eq!(streamed_text(&chunks, 0), expected);
+        assert_eq!(
+            streamed_finish_reason(&chunks, 0).as_deref(),
+            Some("repetition")
+        );
+
+        let body: Value = app
+            .chat("hi", json!({"stream": false}))
+            .await
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], expected);
+        assert_eq!(body["choices"][0]["finish_reason"], "repetition");
+    }
+}
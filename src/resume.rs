@@ -0,0 +1,145 @@
+use axum::response::sse::Event;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+/// Header a reconnecting client sends with the last event id it saw.
+pub const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+
+/// Events kept per stream; a client that missed older ones can't resume.
+const MAX_BUFFERED_EVENTS: usize = 1024;
+
+type SseItem = Result<Event, Infallible>;
+
+/// Recent SSE events per stream, so a client that lost its connection can
+/// reconnect with `Last-Event-Id` and pick up where it left off.
+///
+/// Event ids have the form `<resume token>:<seq>`, counting from 1. The
+/// token is random and only ever sent to the stream's own client, unlike
+/// the response id, so nobody else can attach to the stream.
+pub struct ResumableStreams {
+    ttl: Duration,
+    streams: Arc<DashMap<String, Arc<StreamLog>>>,
+}
+
+struct StreamLog {
+    recorded: Mutex<Recorded>,
+    /// Number of recorded events, and whether the stream has ended.
+    progress: watch::Sender<(usize, bool)>,
+}
+
+/// The last [`MAX_BUFFERED_EVENTS`] events of a stream.
+#[derive(Default)]
+struct Recorded {
+    events: VecDeque<Event>,
+    /// Events recorded so far, including those no longer buffered.
+    total: usize,
+}
+
+impl Recorded {
+    fn push(&mut self, event: Event) {
+        if self.events.len() == MAX_BUFFERED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+        self.total += 1;
+    }
+
+    /// The events after the first `seen`, or `None` if some of them are no
+    /// longer buffered.
+    fn after(&self, seen: usize) -> Option<Vec<Event>> {
+        let first = self.total - self.events.len();
+        let skip = seen.checked_sub(first)?;
+        Some(self.events.iter().skip(skip).cloned().collect())
+    }
+}
+
+impl ResumableStreams {
+    /// A `ttl` of zero disables resumption; events then carry no ids.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            streams: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Numbers and records a stream's events as they are produced, and
+    /// returns the receiver to serve the client from. Recording carries on
+    /// after the client goes away so a reconnect finds every event.
+    pub fn record(&self, mut events: mpsc::Receiver<SseItem>) -> mpsc::Receiver<SseItem> {
+        if self.ttl.is_zero() {
+            return events;
+        }
+        let token = Uuid::new_v4().simple().to_string();
+        let log = Arc::new(StreamLog {
+            recorded: Mutex::new(Recorded::default()),
+            progress: watch::channel((0, false)).0,
+        });
+        self.streams.insert(token.clone(), log.clone());
+
+        let (tx, rx) = mpsc::channel(16);
+        let streams = self.streams.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            while let Some(Ok(event)) = events.recv().await {
+                let (event, total) = {
+                    let mut recorded = log.recorded.lock().unwrap();
+                    let event = event.id(format!("{token}:{}", recorded.total + 1));
+                    recorded.push(event.clone());
+                    (event, recorded.total)
+                };
+                log.progress.send_replace((total, false));
+                let _ = tx.send(Ok(event)).await;
+            }
+            log.progress.send_modify(|(_, finished)| *finished = true);
+            drop(tx);
+            tokio::time::sleep(ttl).await;
+            streams.remove(&token);
+        });
+        rx
+    }
+
+    /// Drops the buffers of streams that have ended, returning how many.
+    /// Running streams keep theirs so their clients can still reconnect.
+    pub fn clear_finished(&self) -> usize {
+        let before = self.streams.len();
+        self.streams.retain(|_, log| !log.progress.borrow().1);
+        before - self.streams.len()
+    }
+
+    /// Replays the events after `last_event_id` and then follows the stream
+    /// until it ends; `None` if the id is malformed or the events it missed
+    /// are no longer buffered. A follower that falls more than
+    /// [`MAX_BUFFERED_EVENTS`] behind is cut off.
+    pub fn resume(&self, last_event_id: &str) -> Option<mpsc::Receiver<SseItem>> {
+        let (token, seq) = last_event_id.rsplit_once(':')?;
+        let mut seen: usize = seq.parse().ok()?;
+        let log = self.streams.get(token)?.clone();
+        log.recorded.lock().unwrap().after(seen)?;
+        let mut progress = log.progress.subscribe();
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let finished = progress.borrow_and_update().1;
+                let Some(pending) = log.recorded.lock().unwrap().after(seen) else {
+                    return;
+                };
+                seen += pending.len();
+                for event in pending {
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+                if finished || progress.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+        Some(rx)
+    }
+}
@@ -0,0 +1,610 @@
+use crate::config::{ServerConfig, TlsConfig};
+use crate::metrics::Metrics;
+use crate::routes::ApiError;
+use anyhow::Context as _;
+use axum::body::{Body, HttpBody};
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::serve::IncomingStream;
+use axum::{Extension, Router};
+use axum_server::accept::NoDelayAcceptor;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use dashmap::DashMap;
+use futures::StreamExt;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::Notify;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::{DefaultOnResponse, MakeSpan, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::{debug, warn, Level, Span};
+
+/// Wraps `router` with an access log: one line per response at `level` with
+/// method, path, status, latency and the `x-request-id` (generated when the
+/// client sends none, and echoed back in the response).
+pub fn with_access_log(router: Router, level: Level) -> Router {
+    router
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(AccessLogSpan { level })
+                .on_request(())
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(level)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+#[derive(Clone)]
+struct AccessLogSpan {
+    level: Level,
+}
+
+impl<B> MakeSpan<B> for AccessLogSpan {
+    fn make_span(&mut self, req: &axum::http::Request<B>) -> Span {
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        // `span!` needs the level as a constant.
+        macro_rules! access_span {
+            ($level:expr) => {
+                tracing::span!(
+                    $level,
+                    "request",
+                    method = %req.method(),
+                    path = %req.uri().path(),
+                    request_id,
+                )
+            };
+        }
+        match self.level {
+            Level::ERROR => access_span!(Level::ERROR),
+            Level::WARN => access_span!(Level::WARN),
+            Level::INFO => access_span!(Level::INFO),
+            Level::DEBUG => access_span!(Level::DEBUG),
+            Level::TRACE => access_span!(Level::TRACE),
+        }
+    }
+}
+
+/// Identifies the client connection a request arrived on. Attached to every
+/// request as `ConnectInfo<ConnectionId>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Connected<IncomingStream<'_>> for ConnectionId {
+    fn connect_info(_: IncomingStream<'_>) -> Self {
+        Self::next()
+    }
+}
+
+/// What `axum_server` hands to the make-service for TLS connections.
+impl Connected<SocketAddr> for ConnectionId {
+    fn connect_info(_: SocketAddr) -> Self {
+        Self::next()
+    }
+}
+
+/// Requests in flight per connection, for `max_requests_per_connection`.
+struct ConnectionLimiter {
+    max: usize,
+    active: DashMap<ConnectionId, usize>,
+}
+
+/// Holds one of a connection's request slots until dropped.
+struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    id: ConnectionId,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Some(mut active) = self.limiter.active.get_mut(&self.id) {
+            *active -= 1;
+        }
+        self.limiter
+            .active
+            .remove_if(&self.id, |_, active| *active == 0);
+    }
+}
+
+/// Answers 429 once a connection has `max` requests in flight, so one
+/// HTTP/2 client can't multiplex an unbounded number of streams.
+pub fn with_connection_limit(router: Router, max: usize) -> Router {
+    let limiter = Arc::new(ConnectionLimiter {
+        max,
+        active: DashMap::new(),
+    });
+    router.layer(axum::middleware::from_fn_with_state(
+        limiter,
+        limit_connection,
+    ))
+}
+
+async fn limit_connection(
+    State(limiter): State<Arc<ConnectionLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(&ConnectInfo(id)) = req.extensions().get::<ConnectInfo<ConnectionId>>() else {
+        return next.run(req).await;
+    };
+    {
+        let mut active = limiter.active.entry(id).or_insert(0);
+        if *active >= limiter.max {
+            return ApiError::ConnectionBusy { limit: limiter.max }.into_response();
+        }
+        *active += 1;
+    }
+    let slot = ConnectionSlot { limiter, id };
+    let response = next.run(req).await;
+    // A streamed response keeps its slot until the body is done; buffered
+    // ones are already complete and keep their Content-Length.
+    if response.body().size_hint().exact().is_some() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &slot;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Serve `router` over a Unix domain socket until `shutdown` resolves.
+///
+/// `axum::serve` only accepts TCP listeners, so connections are driven with
+/// hyper directly. A socket file already at `path` is removed on startup
+/// (stale from an unclean exit) and again once in-flight connections have
+/// drained; any other kind of file there is an error.
+#[cfg(unix)]
+pub async fn serve_unix(
+    path: &str,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => anyhow::bail!("{path} exists and is not a unix socket"),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!(target: "llmis", "unix socket accept failed: {err}");
+                        continue;
+                    }
+                };
+                let router = router.clone().layer(Extension(ConnectInfo(ConnectionId::next())));
+                let service = TowerToHyperService::new(router);
+                let conn = Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .into_owned();
+                let conn = graceful.watch(conn);
+                tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        debug!(target: "llmis", "unix socket connection error: {err}");
+                    }
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+/// Drives `serve` to completion, but once `stopping` fires gives it only
+/// `grace` to drain: requests still running then are cut off.
+pub async fn with_shutdown_grace<E>(
+    serve: impl Future<Output = Result<(), E>>,
+    stopping: Arc<Notify>,
+    grace: Option<Duration>,
+    metrics: &Metrics,
+) -> Result<(), E> {
+    let Some(grace) = grace else {
+        return serve.await;
+    };
+    tokio::pin!(serve);
+    tokio::select! {
+        result = &mut serve => return result,
+        _ = stopping.notified() => {}
+    }
+    match tokio::time::timeout(grace, &mut serve).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                target: "llmis",
+                "shutdown grace of {}s elapsed, force-closing {} in-flight request(s)",
+                grace.as_secs(),
+                metrics.active_requests()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Binds the TCP listener for `host:port` with the configured backlog.
+pub async fn bind_tcp(server: &ServerConfig) -> anyhow::Result<TcpListener> {
+    let addr = tokio::net::lookup_host((server.host.as_str(), server.port))
+        .await?
+        .next()
+        .with_context(|| format!("cannot resolve listen host '{}'", server.host))?;
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Matches `TcpListener::bind`, so a restart can rebind right away.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(server.listen_backlog)?)
+}
+
+/// Serve `router` over HTTPS on `listener` until `shutdown` resolves.
+pub async fn serve_tls(
+    listener: TcpListener,
+    tls: &TlsConfig,
+    tcp_nodelay: bool,
+    router: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let rustls = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+        .await
+        .map_err(|err| {
+            anyhow::anyhow!(
+                "failed to load TLS cert '{}' / key '{}': {err}",
+                tls.cert_path,
+                tls.key_path
+            )
+        })?;
+
+    let handle = Handle::new();
+    let signal_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        signal_handle.graceful_shutdown(None);
+    });
+
+    let server = axum_server::from_tcp_rustls(listener.into_std()?, rustls).handle(handle);
+    if tcp_nodelay {
+        server
+            .map(|acceptor| acceptor.acceptor(NoDelayAcceptor))
+            .serve(router.into_make_service_with_connect_info::<ConnectionId>())
+            .await?;
+    } else {
+        server
+            .serve(router.into_make_service_with_connect_info::<ConnectionId>())
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_config, MockBackend};
+    use axum::body::Body;
+    use axum::routing::get;
+    use futures::StreamExt;
+    use std::future::IntoFuture;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UnixStream};
+
+    fn socket_path() -> String {
+        std::env::temp_dir()
+            .join(format!("llmis-{}.sock", uuid::Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn serve_unix_replaces_a_stale_socket_and_removes_it_on_shutdown() {
+        let path = socket_path();
+        // A socket file left behind by an earlier run.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let mock = MockBackend::start().await;
+        let state = crate::app_state(&test_config(&mock)).unwrap();
+        let router = crate::routes::routes(state);
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                serve_unix(&path, router, async {
+                    let _ = stopped.await;
+                })
+                .await
+            }
+        });
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nhost: llmis\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn serve_unix_refuses_to_replace_a_regular_file() {
+        let path = socket_path();
+        std::fs::write(&path, "keep me").unwrap();
+        let err = serve_unix(&path, Router::new(), std::future::pending())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not a unix socket"), "{err}");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A self-signed certificate for `localhost` and 127.0.0.1.
+    fn test_tls() -> TlsConfig {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        TlsConfig {
+            cert_path: format!("{dir}/cert.pem"),
+            key_path: format!("{dir}/key.pem"),
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_tls_answers_https_with_the_configured_certificate() {
+        let tls = test_tls();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move {
+            serve_tls(listener, &tls, true, router, std::future::pending()).await
+        });
+        let cert = std::fs::read(test_tls().cert_path).unwrap();
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+            .build()
+            .unwrap();
+        let url = format!("https://{addr}/");
+        let resp = loop {
+            match client.get(&url).send().await {
+                Ok(resp) => break resp,
+                Err(err) if err.is_connect() => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{err}"),
+            }
+        };
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        // Plain HTTP on the same port gets no answer.
+        let plain = client.get(format!("http://{addr}/")).send().await;
+        assert!(plain.map_or(true, |resp| !resp.status().is_success()));
+    }
+
+    #[tokio::test]
+    async fn serve_tls_names_the_files_it_could_not_load() {
+        let tls = TlsConfig {
+            cert_path: "/nonexistent/cert.pem".to_string(),
+            key_path: test_tls().key_path,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = serve_tls(listener, &tls, false, Router::new(), std::future::pending())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/cert.pem"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn access_log_records_status_latency_and_request_id() {
+        let (logs, _guard) = crate::testing::capture_logs(Level::INFO);
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            async move { axum::serve(listener, with_access_log(router, Level::INFO)).await },
+        );
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let resp = client
+            .get(format!("http://{addr}/"))
+            .header("x-request-id", "req-42")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["x-request-id"], "req-42");
+        resp.text().await.unwrap();
+        let missing = client
+            .get(format!("http://{addr}/missing"))
+            .send()
+            .await
+            .unwrap();
+        let generated = missing.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let text = logs.text();
+        let line = text.lines().find(|line| line.contains("req-42")).unwrap();
+        assert!(line.contains("method=GET path=/"), "{line}");
+        assert!(line.contains("status=200"), "{line}");
+        assert!(line.contains("latency=") && line.contains(" ms"), "{line}");
+        let line = text.lines().find(|line| line.contains(&generated)).unwrap();
+        assert!(line.contains("status=404"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn access_log_level_filters_the_lines() {
+        let (logs, _guard) = crate::testing::capture_logs(Level::INFO);
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            async move { axum::serve(listener, with_access_log(router, Level::DEBUG)).await },
+        );
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let resp = client.get(format!("http://{addr}/")).send().await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        assert!(!logs.text().contains("status="), "{}", logs.text());
+    }
+
+    #[tokio::test]
+    async fn bind_tcp_serves_with_backlog_and_nodelay_set() {
+        let server = ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            listen_backlog: 8,
+            tcp_nodelay: true,
+            ..Default::default()
+        };
+        let listener = bind_tcp(&server).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move {
+            axum::serve(listener, router)
+                .tcp_nodelay(server.tcp_nodelay)
+                .await
+        });
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        for _ in 0..3 {
+            let resp = client.get(format!("http://{addr}/")).send().await.unwrap();
+            assert_eq!(resp.text().await.unwrap(), "ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_grace_force_closes_a_never_ending_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/",
+            get(|| async {
+                let forever = futures::stream::pending::<Result<Vec<u8>, std::io::Error>>();
+                Body::from_stream(futures::stream::iter([Ok(b"started".to_vec())]).chain(forever))
+            }),
+        );
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let stopping = Arc::new(tokio::sync::Notify::new());
+        let shutdown = {
+            let stopping = stopping.clone();
+            async move {
+                let _ = stopped.await;
+                stopping.notify_one();
+            }
+        };
+        let serve = axum::serve(listener, router)
+            .with_graceful_shutdown(shutdown)
+            .into_future();
+        let metrics = Metrics::default();
+        let server = tokio::spawn(async move {
+            with_shutdown_grace(serve, stopping, Some(Duration::from_millis(300)), &metrics).await
+        });
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let mut resp = client.get(format!("http://{addr}/")).send().await.unwrap();
+        assert_eq!(&resp.chunk().await.unwrap().unwrap()[..], b"started");
+        let started = std::time::Instant::now();
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server kept running past the grace period")
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn connection_limit_throttles_excess_streams_on_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "ok"
+            }),
+        );
+        let router = with_connection_limit(router, 2);
+        tokio::spawn(async move {
+            serve_tls(listener, &test_tls(), true, router, std::future::pending()).await
+        });
+        let cert = std::fs::read(test_tls().cert_path).unwrap();
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let url = format!("https://localhost:{}/", addr.port());
+        // One request first, so the rest share its HTTP/2 connection.
+        let first = loop {
+            match client.get(&url).send().await {
+                Ok(resp) => break resp,
+                Err(err) if err.is_connect() => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(err) => panic!("{err}"),
+            }
+        };
+        assert_eq!(first.version(), reqwest::Version::HTTP_2);
+
+        let responses = futures::future::join_all((0..5).map(|_| client.get(&url).send())).await;
+        let mut statuses: Vec<u16> = Vec::new();
+        for resp in responses {
+            let resp = resp.unwrap();
+            statuses.push(resp.status().as_u16());
+            if resp.status() == 429 {
+                let body: serde_json::Value = resp.json().await.unwrap();
+                assert_eq!(
+                    body["error"],
+                    "at most 2 concurrent requests are allowed per connection"
+                );
+            }
+        }
+        statuses.sort();
+        assert_eq!(statuses, [200, 200, 429, 429, 429]);
+
+        // Finished requests give their slots back.
+        assert_eq!(client.get(&url).send().await.unwrap().status(), 200);
+    }
+}
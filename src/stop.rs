@@ -0,0 +1,97 @@
+/// Returns the byte offset and length of the stop sequence that matches
+/// earliest in `text`. When several stops match at the same offset the
+/// longest one wins, so overlapping stops truncate deterministically.
+pub fn earliest_stop(text: &str, stops: &[String]) -> Option<(usize, usize)> {
+    stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()).map(|idx| (idx, stop.len())))
+        .min_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+}
+
+/// Incremental stop-sequence matcher for streamed tokens.
+///
+/// Text that could still be the start of a stop sequence is held back until
+/// the next token disambiguates it, so a stop split across tokens is never
+/// partially emitted.
+pub struct StopMatcher {
+    stops: Vec<String>,
+    pending: String,
+    /// Emit the matched stop sequence rather than trimming it.
+    include_stop: bool,
+}
+
+impl StopMatcher {
+    pub fn new(stops: Vec<String>, include_stop: bool) -> Self {
+        Self {
+            stops,
+            pending: String::new(),
+            include_stop,
+        }
+    }
+
+    /// Feeds one token and returns the text that is safe to emit, plus
+    /// whether a stop sequence matched (in which case generation should end).
+    pub fn push(&mut self, token: &str) -> (String, bool) {
+        self.pending.push_str(token);
+        if let Some((idx, len)) = earliest_stop(&self.pending, &self.stops) {
+            let end = if self.include_stop { idx + len } else { idx };
+            let out = self.pending[..end].to_string();
+            self.pending.clear();
+            return (out, true);
+        }
+        let split = self.pending.len() - self.holdback();
+        let out = self.pending[..split].to_string();
+        self.pending.drain(..split);
+        (out, false)
+    }
+
+    /// Releases any held-back text once the stream ends without a stop.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn holdback(&self) -> usize {
+        self.stops
+            .iter()
+            .flat_map(|stop| {
+                (1..stop.len())
+                    .filter(|&k| stop.is_char_boundary(k) && self.pending.ends_with(&stop[..k]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(stops: &[&str]) -> Vec<String> {
+        stops.iter().map(|stop| stop.to_string()).collect()
+    }
+
+    #[test]
+    fn earliest_stop_prefers_the_earliest_then_the_longest_match() {
+        let text = "one two three";
+        assert_eq!(earliest_stop(text, &stops(&["three", "two"])), Some((4, 3)));
+        assert_eq!(earliest_stop(text, &stops(&["tw", "two t"])), Some((4, 5)));
+        assert_eq!(earliest_stop(text, &stops(&["", "four"])), None);
+    }
+
+    #[test]
+    fn matcher_holds_back_split_stops_and_stops_at_the_earliest() {
+        let mut matcher = StopMatcher::new(stops(&["world", "lo w"]), false);
+        assert_eq!(matcher.push("hel"), ("he".to_string(), false));
+        // "lo w" completes before "world" could, and starts earlier.
+        assert_eq!(matcher.push("lo wor"), ("l".to_string(), true));
+
+        let mut matcher = StopMatcher::new(stops(&["END"]), true);
+        assert_eq!(matcher.push("done E"), ("done ".to_string(), false));
+        assert_eq!(matcher.push("ND later"), ("END".to_string(), true));
+
+        let mut matcher = StopMatcher::new(stops(&["END"]), false);
+        assert_eq!(matcher.push("almost EN"), ("almost ".to_string(), false));
+        assert_eq!(matcher.flush(), "EN");
+    }
+}
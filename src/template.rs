@@ -0,0 +1,107 @@
+use crate::model::ModelError;
+use minijinja::{context, Environment};
+use serde::Serialize;
+
+const TEMPLATE_NAME: &str = "chat";
+
+/// A Jinja chat template, in the style of Hugging Face `chat_template`s,
+/// compiled once when its model is loaded.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+}
+
+impl ChatTemplate {
+    pub fn from_file(path: &str) -> Result<Self, ModelError> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| ModelError::Template(format!("cannot read template '{path}': {err}")))?;
+        Self::compile(source)
+            .map_err(|err| ModelError::Template(format!("invalid template '{path}': {err}")))
+    }
+
+    fn compile(source: String) -> Result<Self, minijinja::Error> {
+        let mut env = Environment::new();
+        env.add_template_owned(TEMPLATE_NAME, source)?;
+        Ok(Self { env })
+    }
+
+    /// Renders `messages` (each with `role` and `content`), ending with the
+    /// assistant turn's opening so the model continues from there.
+    pub fn render<M: Serialize>(&self, messages: &[M]) -> Result<String, ModelError> {
+        self.env
+            .get_template(TEMPLATE_NAME)
+            .and_then(|template| {
+                template.render(context! {
+                    messages => messages,
+                    add_generation_prompt => true,
+                })
+            })
+            .map_err(|err| {
+                ModelError::TemplateRender(format!("failed to render chat template: {err}"))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_config, MockBackend, TestApp};
+    use serde_json::json;
+
+    const CHATML: &str = "{% for m in messages %}<|im_start|>{{ m.role }}\n{{ m.content }}<|im_end|>\n{% endfor %}{% if add_generation_prompt %}<|im_start|>assistant\n{% endif %}";
+
+    fn template_file(source: &str) -> String {
+        let path = std::env::temp_dir().join(format!("llmis-{}.jinja", uuid::Uuid::new_v4()));
+        std::fs::write(&path, source).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn template_file_renders_chat_prompts() {
+        let path = template_file(CHATML);
+        let template = ChatTemplate::from_file(&path).unwrap();
+        let messages = [
+            json!({"role": "system", "content": "Be brief."}),
+            json!({"role": "user", "content": "Hi"}),
+        ];
+        let expected = "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n";
+        assert_eq!(template.render(&messages).unwrap(), expected);
+
+        let mock = MockBackend::replying(&["ok"]).await;
+        let mut cfg = test_config(&mock);
+        cfg.models[0].template_path = Some(path.clone());
+        let app = TestApp::start(cfg).await;
+        let resp = app
+            .post(
+                "/v1/chat/completions",
+                json!({"model": "m", "stream": false, "messages": messages}),
+            )
+            .await;
+        assert_eq!(resp.status(), 200);
+        let sent = &mock.generations()[0]["messages"];
+        assert_eq!(
+            sent[sent.as_array().unwrap().len() - 1]["content"],
+            expected
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_template_fails_with_its_path() {
+        let path = template_file("{% for m in messages %}{{ m.content }");
+        let err = ChatTemplate::from_file(&path).err().unwrap();
+        assert!(matches!(err, ModelError::Template(_)));
+        assert!(
+            err.to_string()
+                .contains(&format!("invalid template '{path}'")),
+            "{err}"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let err = ChatTemplate::from_file("/nonexistent/chat.jinja")
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("cannot read template '/nonexistent/chat.jinja'"));
+    }
+}
@@ -0,0 +1,621 @@
+//! Test support: a scriptable stand-in for llama-server and the service
+//! wired up the way `main` does it, both listening on ephemeral ports.
+// Each test uses only some of the helpers.
+#![allow(dead_code)]
+
+use crate::config::{AppConfig, ModelConfig, Secret};
+use crate::model::GenerateParams;
+use crate::routes::AppState;
+use crate::server::ConnectionId;
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Name of the model [`test_config`] registers.
+pub const MODEL: &str = "m";
+/// Bearer token [`test_config`] sets for the admin routes.
+pub const ADMIN_KEY: &str = "admin-key";
+
+/// What a [`MockBackend`] does with the requests it gets.
+#[derive(Clone)]
+pub struct Script {
+    /// Text chunks to stream; by default the last message is echoed back a
+    /// word at a time.
+    pub reply: Option<Vec<String>>,
+    /// Replies of successive generations, cycling; used instead of `reply`
+    /// when not empty.
+    pub replies: Vec<Vec<String>>,
+    /// Pause before each streamed event.
+    pub delay: Duration,
+    /// Sent on the finishing chunk; `"stop"`, or `"length"` when
+    /// `max_tokens` cut the reply short, if unset.
+    pub finish_reason: Option<String>,
+    /// End with a bare `[DONE]`, like older servers, with no finishing chunk.
+    pub no_finish_reason: bool,
+    /// Send an event outside the OpenAI chunk format after this many chunks.
+    pub garbage_after: Option<usize>,
+    /// Break the connection after this many chunks, for the first `drops`
+    /// generations.
+    pub drop_after: Option<usize>,
+    pub drops: usize,
+    /// Per-chunk log-probability of successive generations that ask for
+    /// logprobs, cycling like `replies`; -1.0 when empty.
+    pub logprobs: Vec<f32>,
+    /// Answer generations with this status instead of a stream.
+    pub fail_status: Option<u16>,
+    /// Pause before answering a generation at all, like a backend that hangs.
+    pub answer_delay: Duration,
+    /// Status of `GET /health`.
+    pub health: u16,
+    /// Pause before answering `GET /health`, for loads that take a while.
+    pub health_delay: Duration,
+    /// Which detection endpoints answer, for `backend = "auto"`.
+    pub kind: MockKind,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            reply: None,
+            replies: Vec::new(),
+            delay: Duration::ZERO,
+            finish_reason: None,
+            no_finish_reason: false,
+            garbage_after: None,
+            drop_after: None,
+            drops: 1,
+            logprobs: Vec::new(),
+            fail_status: None,
+            answer_delay: Duration::ZERO,
+            health: 200,
+            health_delay: Duration::ZERO,
+            kind: MockKind::LlamaServer,
+        }
+    }
+}
+
+/// `count` chunks `" t0"`, `" t1"`, ..., for replies longer than any limit
+/// under test.
+pub fn numbered(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!(" t{i}")).collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MockKind {
+    LlamaServer,
+    Ollama,
+    Tgi,
+    Vllm,
+}
+
+/// A request the mock received.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    /// Client end of the connection it arrived on.
+    pub peer: SocketAddr,
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Value,
+}
+
+struct MockState {
+    script: Mutex<Script>,
+    requests: Mutex<Vec<Recorded>>,
+    generations: AtomicUsize,
+    /// Requests being answered now, and the most there have been at once.
+    handling: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// An OpenAI-compatible backend speaking llama-server's streaming dialect,
+/// with tool calls, prefill progress and vLLM-style `prompt_logprobs` when a
+/// request asks for them.
+pub struct MockBackend {
+    pub url: String,
+    state: Arc<MockState>,
+}
+
+impl MockBackend {
+    pub async fn start() -> Self {
+        Self::with_script(Script::default()).await
+    }
+
+    /// A mock that streams `reply` to every generation.
+    pub async fn replying(reply: &[&str]) -> Self {
+        Self::with_script(Script {
+            reply: Some(reply.iter().map(|chunk| chunk.to_string()).collect()),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn with_script(script: Script) -> Self {
+        let state = Arc::new(MockState {
+            script: Mutex::new(script),
+            requests: Mutex::new(Vec::new()),
+            generations: AtomicUsize::new(0),
+            handling: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        });
+        let router = Router::new()
+            .fallback(mock_handler)
+            .with_state(state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        // Small SSE writes on a reused connection would otherwise wait on
+        // delayed ACKs.
+        let serve = axum::serve(listener, service).tcp_nodelay(true);
+        tokio::spawn(async move { serve.await });
+        Self { url, state }
+    }
+
+    /// Changes the script for requests from now on.
+    pub fn script(&self, update: impl FnOnce(&mut Script)) {
+        update(&mut self.state.script.lock().unwrap());
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// The most requests the mock was answering at once, not counting
+    /// streamed bodies still being sent.
+    pub fn peak_concurrency(&self) -> usize {
+        self.state.peak.load(Ordering::SeqCst)
+    }
+
+    /// How many distinct connections requests arrived on.
+    pub fn connections(&self) -> usize {
+        let mut peers: Vec<SocketAddr> = self.requests().iter().map(|r| r.peer).collect();
+        peers.sort();
+        peers.dedup();
+        peers.len()
+    }
+
+    /// Bodies of the generation requests received so far.
+    pub fn generations(&self) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == "/v1/chat/completions")
+            .map(|r| r.body)
+            .collect()
+    }
+}
+
+async fn mock_handler(
+    State(state): State<Arc<MockState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let handling = state.handling.fetch_add(1, Ordering::SeqCst) + 1;
+    state.peak.fetch_max(handling, Ordering::SeqCst);
+    let response = answer(&state, peer, method, uri, headers, body).await;
+    state.handling.fetch_sub(1, Ordering::SeqCst);
+    response
+}
+
+async fn answer(
+    state: &MockState,
+    peer: SocketAddr,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    let path = uri.path().to_string();
+    state.requests.lock().unwrap().push(Recorded {
+        peer,
+        method: method.clone(),
+        path: path.clone(),
+        headers,
+        body: body.clone(),
+    });
+    let script = state.script.lock().unwrap().clone();
+    let kind = script.kind;
+    match (method, path.as_str()) {
+        (Method::GET, "/health") => {
+            tokio::time::sleep(script.health_delay).await;
+            StatusCode::from_u16(script.health).unwrap().into_response()
+        }
+        (Method::GET, "/v1/models") if kind == MockKind::Vllm => {
+            Json(json!({"data": [{"id": "mock", "owned_by": "vllm"}]})).into_response()
+        }
+        (Method::GET, "/v1/models") => Json(json!({"data": []})).into_response(),
+        (Method::GET, "/api/tags") if kind == MockKind::Ollama => {
+            Json(json!({"models": []})).into_response()
+        }
+        (Method::GET, "/info") if kind == MockKind::Tgi => {
+            Json(json!({"model_id": "mock"})).into_response()
+        }
+        (Method::POST, "/tokenize") => {
+            let words = body["content"].as_str().unwrap_or("").split_whitespace();
+            let tokens: Vec<usize> = (0..words.count()).collect();
+            Json(json!({ "tokens": tokens })).into_response()
+        }
+        (Method::POST, "/v1/chat/completions") => {
+            tokio::time::sleep(script.answer_delay).await;
+            generation(state, script, &body)
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn generation(state: &MockState, script: Script, body: &Value) -> Response {
+    if let Some(status) = script.fail_status {
+        return StatusCode::from_u16(status).unwrap().into_response();
+    }
+    let generation = state.generations.fetch_add(1, Ordering::SeqCst);
+    let mut events: Vec<Value> = Vec::new();
+    let chunk = |delta: Value, finish_reason: Option<&str>| json!({"choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]});
+    if body["return_progress"].as_bool() == Some(true) {
+        for processed in [50, 100] {
+            let mut event = chunk(json!({}), None);
+            event["prompt_progress"] = json!({"total": 100, "cache": 0, "processed": processed});
+            events.push(event);
+        }
+    }
+    if let Some(tool) = body["tools"][0]["function"]["name"].as_str() {
+        events.push(chunk(
+            json!({"tool_calls": [{"index": 0, "id": "call_1", "type": "function",
+                "function": {"name": tool, "arguments": ""}}]}),
+            None,
+        ));
+        for fragment in ["{\"loc", "ation\": \"Par", "is\"}"] {
+            events.push(chunk(
+                json!({"tool_calls": [{"index": 0, "function": {"arguments": fragment}}]}),
+                None,
+            ));
+        }
+        events.push(chunk(json!({}), Some("tool_calls")));
+        return sse_body(events, script.delay, None);
+    }
+    let prompt = body["messages"]
+        .as_array()
+        .and_then(|messages| messages.last())
+        .and_then(|message| message["content"].as_str())
+        .unwrap_or("")
+        .to_string();
+    if body["prompt_logprobs"].is_number() {
+        let positions: Vec<Value> = std::iter::once(Value::Null)
+            .chain(
+                prompt
+                    .split(' ')
+                    .skip(1)
+                    .enumerate()
+                    .map(|(i, word)| json!({ (i + 1).to_string(): {"logprob": -0.5, "rank": 1, "decoded_token": word} })),
+            )
+            .collect();
+        let mut event = chunk(json!({"role": "assistant"}), None);
+        event["prompt_logprobs"] = Value::Array(positions);
+        events.push(event);
+    }
+    let scripted = match script.replies.len() {
+        0 => script.reply.clone(),
+        len => Some(script.replies[generation % len].clone()),
+    };
+    let reply = scripted.unwrap_or_else(|| {
+        prompt
+            .split(' ')
+            .enumerate()
+            .map(|(i, word)| {
+                if i == 0 {
+                    word.to_string()
+                } else {
+                    format!(" {word}")
+                }
+            })
+            .collect()
+    });
+    let logprob = (body["logprobs"].as_bool() == Some(true)).then(|| match script.logprobs.len() {
+        0 => -1.0,
+        len => script.logprobs[generation % len],
+    });
+    let max_tokens = body["max_tokens"].as_u64().unwrap_or(u64::MAX) as usize;
+    let mut finish_reason = script
+        .finish_reason
+        .clone()
+        .unwrap_or_else(|| "stop".to_string());
+    let mut drop_at = script
+        .drop_after
+        .filter(|_| generation < script.drops)
+        .map(|after| events.len() + after);
+    for (i, text) in reply.iter().enumerate() {
+        if i >= max_tokens {
+            finish_reason = "length".to_string();
+            break;
+        }
+        if script.garbage_after == Some(i) {
+            events.push(json!({"oops": true}));
+        }
+        let mut event = chunk(json!({ "content": text }), None);
+        if let Some(logprob) = logprob {
+            event["choices"][0]["logprobs"] =
+                json!({"content": [{"token": text, "logprob": logprob}]});
+        }
+        events.push(event);
+    }
+    if drop_at.is_some_and(|at| at >= events.len()) {
+        drop_at = None;
+    }
+    if !script.no_finish_reason {
+        events.push(chunk(json!({}), Some(finish_reason.as_str())));
+    }
+    sse_body(events, script.delay, drop_at)
+}
+
+/// Streams `events` as SSE followed by `[DONE]`, or breaks the connection
+/// after `drop_at` of them.
+fn sse_body(events: Vec<Value>, delay: Duration, drop_at: Option<usize>) -> Response {
+    let mut frames: Vec<Result<Bytes, std::io::Error>> = events
+        .iter()
+        .map(|event| Ok(Bytes::from(format!("data: {event}\n\n"))))
+        .collect();
+    frames.push(Ok(Bytes::from_static(b"data: [DONE]\n\n")));
+    if let Some(at) = drop_at {
+        frames.truncate(at);
+        frames.push(Err(std::io::Error::other("dropped")));
+    }
+    let stream = futures::stream::iter(frames).then(move |frame| async move {
+        tokio::time::sleep(delay).await;
+        frame
+    });
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+/// Defaults plus [`ADMIN_KEY`] and one llama-server model, [`MODEL`],
+/// served by `mock`.
+pub fn test_config(mock: &MockBackend) -> AppConfig {
+    AppConfig {
+        admin_api_key: Some(Secret::new(ADMIN_KEY.to_string())),
+        models: vec![model_config(MODEL, mock)],
+        ..Default::default()
+    }
+}
+
+pub fn model_config(name: &str, mock: &MockBackend) -> ModelConfig {
+    ModelConfig {
+        name: name.to_string(),
+        backend: Some("llama-server".to_string()),
+        server_url: Some(mock.url.clone()),
+        ..Default::default()
+    }
+}
+
+/// Sampling defaults for generating from `prompt` directly through a
+/// model handle.
+pub fn params(prompt: &str) -> GenerateParams {
+    GenerateParams {
+        prompt: prompt.to_string(),
+        max_tokens: 256,
+        temperature: 0.7,
+        top_p: 0.95,
+        presence_penalty: None,
+        frequency_penalty: None,
+        stop: None,
+        cache_prompt: false,
+        session_id: None,
+        logit_bias: None,
+        return_progress: false,
+        extra: None,
+        logprobs: false,
+        request_id: None,
+        tools: None,
+        tool_choice: None,
+        backend_url: None,
+        seed: None,
+        reconnects: 0,
+        include_stop: false,
+        prompt_logprobs: None,
+    }
+}
+
+/// The service on an ephemeral port, with its configured models loaded.
+pub struct TestApp {
+    pub url: String,
+    pub state: AppState,
+    pub client: reqwest::Client,
+}
+
+impl TestApp {
+    /// Serves a mock backend with the default script and [`test_config`].
+    pub async fn with_mock() -> (Self, MockBackend) {
+        let mock = MockBackend::start().await;
+        (Self::start(test_config(&mock)).await, mock)
+    }
+
+    pub async fn start(cfg: AppConfig) -> Self {
+        let state = crate::app_state(&cfg).unwrap();
+        for model in &cfg.models {
+            if !cfg.eviction.include_config_models {
+                state.models.pin(&model.name);
+            }
+            state.models.load_model(model.clone(), false).await.unwrap();
+        }
+        let router = crate::app_router(state.clone(), &cfg).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let service = router.into_make_service_with_connect_info::<ConnectionId>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        Self { url, state, client }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.client.request(method, format!("{}{path}", self.url))
+    }
+
+    /// A request to an admin route, authorized with [`ADMIN_KEY`].
+    pub fn admin(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.request(method, path).bearer_auth(ADMIN_KEY)
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Response {
+        self.request(Method::GET, path).send().await.unwrap()
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> reqwest::Response {
+        self.request(Method::POST, path)
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// A chat completion for [`MODEL`] with `extra` merged into the body.
+    pub async fn chat(&self, content: &str, extra: Value) -> reqwest::Response {
+        let mut body = json!({
+            "model": MODEL,
+            "messages": [{"role": "user", "content": content}],
+        });
+        merge(&mut body, extra);
+        self.post("/v1/chat/completions", body).await
+    }
+
+    /// A legacy completion for [`MODEL`] with `extra` merged into the body.
+    pub async fn complete(&self, prompt: &str, extra: Value) -> reqwest::Response {
+        let mut body = json!({ "model": MODEL, "prompt": prompt });
+        merge(&mut body, extra);
+        self.post("/v1/completions", body).await
+    }
+}
+
+fn merge(body: &mut Value, extra: Value) {
+    if let (Some(body), Value::Object(extra)) = (body.as_object_mut(), extra) {
+        body.extend(extra);
+    }
+}
+
+/// One server-sent event.
+#[derive(Debug, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: String,
+    pub comment: Option<String>,
+}
+
+/// Reads a whole SSE response.
+pub async fn sse(resp: reqwest::Response) -> Vec<SseEvent> {
+    parse_sse(&resp.text().await.unwrap())
+}
+
+/// Reads at least `count` events from a streaming response, leaving the
+/// rest of it unread.
+pub async fn sse_prefix(resp: &mut reqwest::Response, count: usize) -> Vec<SseEvent> {
+    let mut text = String::new();
+    while text.matches("\n\n").count() < count {
+        let chunk = resp.chunk().await.unwrap().expect("stream ended early");
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    let complete = text.rfind("\n\n").unwrap();
+    parse_sse(&text[..complete])
+}
+
+/// Parses complete server-sent events.
+pub fn parse_sse(text: &str) -> Vec<SseEvent> {
+    text.split("\n\n")
+        .filter(|block| !block.trim().is_empty())
+        .map(|block| {
+            let mut event = SseEvent::default();
+            for line in block.lines() {
+                if let Some(data) = line.strip_prefix("data:") {
+                    event.data.push_str(data.trim_start());
+                } else if let Some(name) = line.strip_prefix("event:") {
+                    event.event = Some(name.trim().to_string());
+                } else if let Some(id) = line.strip_prefix("id:") {
+                    event.id = Some(id.trim().to_string());
+                } else if let Some(comment) = line.strip_prefix(':') {
+                    event.comment = Some(comment.trim().to_string());
+                }
+            }
+            event
+        })
+        .collect()
+}
+
+/// The JSON payloads of a stream's data events, without `[DONE]`.
+pub fn chunks(events: &[SseEvent]) -> Vec<Value> {
+    events
+        .iter()
+        .filter(|event| event.event.is_none())
+        .filter_map(|event| serde_json::from_str(&event.data).ok())
+        .collect()
+}
+
+/// Text of choice `index` across streamed chat or completion chunks.
+pub fn streamed_text(chunks: &[Value], index: u64) -> String {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk["choices"].as_array().cloned().unwrap_or_default())
+        .filter(|choice| choice["index"].as_u64() == Some(index))
+        .filter_map(|choice| {
+            choice["delta"]["content"]
+                .as_str()
+                .or(choice["text"].as_str())
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// The `finish_reason` of choice `index`, from the chunk that carries one.
+pub fn streamed_finish_reason(chunks: &[Value], index: u64) -> Option<String> {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk["choices"].as_array().cloned().unwrap_or_default())
+        .filter(|choice| choice["index"].as_u64() == Some(index))
+        .find_map(|choice| choice["finish_reason"].as_str().map(str::to_string))
+}
+
+/// Log lines written while a [`capture_logs`] guard is alive.
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records events up to `level` on this thread, which with the default
+/// single-threaded test runtime includes spawned servers.
+pub fn capture_logs(level: tracing::Level) -> (CapturedLogs, tracing::subscriber::DefaultGuard) {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        })
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
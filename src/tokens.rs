@@ -0,0 +1,106 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::Duration;
+
+/// Longest a `/tokenize` call may take before the heuristic is used instead.
+const TOKENIZE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Strategy used to count tokens for usage and metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenCounterKind {
+    /// ~4 characters per token; free but approximate.
+    #[default]
+    Heuristic,
+    /// One token per whitespace-separated word.
+    Whitespace,
+    /// Ask the backend's `/tokenize` endpoint; exact but costs a round-trip.
+    Backend,
+}
+
+#[async_trait]
+pub trait TokenCounter: Send + Sync {
+    async fn count(&self, text: &str) -> usize;
+}
+
+pub struct HeuristicCounter;
+
+#[async_trait]
+impl TokenCounter for HeuristicCounter {
+    async fn count(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+pub struct WhitespaceCounter;
+
+#[async_trait]
+impl TokenCounter for WhitespaceCounter {
+    async fn count(&self, text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+}
+
+/// Delegates to llama.cpp's `/tokenize`, falling back to the heuristic when
+/// the backend is unreachable, slow or returns something unexpected.
+pub struct BackendCounter {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl BackendCounter {
+    pub fn new(client: reqwest::Client, server_url: &str) -> Self {
+        Self {
+            client,
+            url: format!("{}/tokenize", server_url),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenCounter for BackendCounter {
+    async fn count(&self, text: &str) -> usize {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&serde_json::json!({ "content": text }))
+            .timeout(TOKENIZE_TIMEOUT)
+            .send()
+            .await;
+        let tokens = match response {
+            Ok(resp) => resp
+                .json::<Value>()
+                .await
+                .ok()
+                .and_then(|v| v.get("tokens").and_then(|t| t.as_array()).map(|t| t.len())),
+            Err(_) => None,
+        };
+        match tokens {
+            Some(count) => count,
+            None => HeuristicCounter.count(text).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockBackend;
+
+    #[tokio::test]
+    async fn strategies_count_a_known_string() {
+        let text = "The quick brown fox jumps";
+        assert_eq!(HeuristicCounter.count(text).await, 7);
+        assert_eq!(WhitespaceCounter.count(text).await, 5);
+
+        // The mock tokenizes one token per word.
+        let mock = MockBackend::start().await;
+        let backend = BackendCounter::new(reqwest::Client::new(), &mock.url);
+        assert_eq!(backend.count(text).await, 5);
+        assert_eq!(mock.requests().last().unwrap().body["content"], text);
+
+        let unreachable = BackendCounter::new(reqwest::Client::new(), "http://127.0.0.1:1");
+        assert_eq!(unreachable.count(text).await, 7);
+    }
+}